@@ -0,0 +1,234 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::header;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    auth::{errors::*, parse_config, unauthorized, AuthResult},
+    config::def::{AuthType, Identity},
+    core::plugin::{Plugin, PluginCtx},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct BasicAuthConfig {
+    /// Realm sent in the `WWW-Authenticate` challenge
+    realm: String,
+    /// Removes the `Authorization` header before proxying to upstream
+    hide_credentials: bool,
+}
+
+impl Default for BasicAuthConfig {
+    fn default() -> Self {
+        Self {
+            realm: "penguin".to_string(),
+            hide_credentials: false,
+        }
+    }
+}
+
+pub fn create_basic_auth(
+    cfg: Option<YamlValue>,
+    identities: &[&Identity],
+) -> AuthResult<Box<dyn Plugin>> {
+    let config: BasicAuthConfig = parse_config(cfg, AuthType::BasicAuth)?;
+    let mut credentials = HashMap::new();
+    for identity in identities {
+        let Some(basic) = &identity.basic_auth else {
+            continue;
+        };
+        let password = Password::parse(&basic.password).ok_or(AuthError::InvalidPasswordHash {
+            identity: identity.name.clone(),
+        })?;
+        let other = credentials.insert(
+            basic.username.clone(),
+            Credential {
+                identity: identity.name.clone(),
+                password,
+            },
+        );
+        if let Some(other) = other {
+            return Err(AuthError::DuplicateUsername {
+                username: basic.username.clone(),
+                identity: identity.name.clone(),
+                other: other.identity,
+            });
+        }
+    }
+    if credentials.is_empty() {
+        return Err(AuthError::NoCredential {
+            auth_type: AuthType::BasicAuth,
+        });
+    }
+    let dummy = credentials
+        .values()
+        .map(|credential| &credential.password)
+        .find(|password| !matches!(password, Password::Plain(_)))
+        .cloned();
+    Ok(Box::new(BasicAuthPlugin {
+        credentials: Arc::new(credentials),
+        dummy,
+        challenge: format!("Basic realm=\"{}\"", config.realm),
+        hide_credentials: config.hide_credentials,
+    }))
+}
+
+struct Credential {
+    identity: String,
+    password: Password,
+}
+
+#[derive(Clone)]
+enum Password {
+    Plain(String),
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Password {
+    /// Detects the hash algorithm from the prefix, returns None if the hash is malformed
+    fn parse(password: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            bcrypt::HashParts::from_str(password)
+                .ok()
+                .filter(|parts| (4..=31).contains(&parts.get_cost()))?;
+            return Some(Password::Bcrypt(password.to_string()));
+        }
+        if password.starts_with("$argon2") {
+            PasswordHash::new(password).ok()?;
+            return Some(Password::Argon2(password.to_string()));
+        }
+        Some(Password::Plain(password.to_string()))
+    }
+
+    /// Verifies the password, hashes are verified in a blocking thread since they are slow on purpose
+    async fn verify(&self, input: String) -> bool {
+        match self {
+            Password::Plain(expected) => expected.as_bytes().ct_eq(input.as_bytes()).into(),
+            Password::Bcrypt(hash) => {
+                let hash = hash.clone();
+                tokio::task::spawn_blocking(move || bcrypt::verify(input, &hash).unwrap_or(false))
+                    .await
+                    .unwrap_or(false)
+            }
+            Password::Argon2(hash) => {
+                let hash = hash.clone();
+                tokio::task::spawn_blocking(move || {
+                    PasswordHash::new(&hash).is_ok_and(|hash| {
+                        Argon2::default()
+                            .verify_password(input.as_bytes(), &hash)
+                            .is_ok()
+                    })
+                })
+                .await
+                .unwrap_or(false)
+            }
+        }
+    }
+}
+
+pub struct BasicAuthPlugin {
+    /// Credentials indexed by username
+    credentials: Arc<HashMap<String, Credential>>,
+    /// Hash verified for unknown usernames, so that they take as long to reject as wrong
+    /// passwords and don't reveal which usernames exist
+    dummy: Option<Password>,
+    challenge: String,
+    hide_credentials: bool,
+}
+
+impl BasicAuthPlugin {
+    /// Extracts the username and password from the `Authorization` header
+    fn parse_authorization(session: &Session) -> Option<(String, String)> {
+        let value = session
+            .req_header()
+            .headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?;
+        let (scheme, encoded) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+}
+
+#[async_trait]
+impl Plugin for BasicAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        if let Some((username, password)) = Self::parse_authorization(session) {
+            match self.credentials.get(&username) {
+                Some(credential) => {
+                    if credential.password.verify(password).await {
+                        ctx.identity = Some(credential.identity.clone());
+                        return Ok(false);
+                    }
+                }
+                None => {
+                    if let Some(dummy) = &self.dummy {
+                        dummy.verify(password).await;
+                    }
+                }
+            }
+        }
+        unauthorized(session, self.challenge.clone()).await?;
+        Ok(true)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if self.hide_credentials {
+            upstream_request.remove_header(&header::AUTHORIZATION);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identities(yaml: &str) -> Vec<Identity> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn malformed_bcrypt_hashes_are_rejected() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(matches!(Password::parse(&hash), Some(Password::Bcrypt(_))));
+        assert!(Password::parse("$2b$12$tooshort").is_none());
+        assert!(Password::parse(&hash.replacen("$04$", "$99$", 1)).is_none());
+        assert!(matches!(Password::parse("plain"), Some(Password::Plain(_))));
+    }
+
+    #[test]
+    fn usernames_are_unique_across_identities() {
+        let identities = identities(
+            r#"
+            - {name: a, basic_auth: {username: u, password: x}}
+            - {name: b, basic_auth: {username: u, password: y}}
+            "#,
+        );
+        let identities: Vec<_> = identities.iter().collect();
+        assert!(matches!(
+            create_basic_auth(None, &identities),
+            Err(AuthError::DuplicateUsername { .. })
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use pingora::{connectors::http::Connector, http::ResponseHeader, prelude::*};
+
+use crate::{
+    auth::{errors::*, AuthResult},
+    clusters::ClusterManager,
+    core::lb::LB,
+};
+
+/// Responses of external services larger than this are treated as failures
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Sends subrequests to the external service behind a cluster
+pub struct ClusterClient {
+    cluster: String,
+    lb: Arc<dyn LB>,
+    connector: Connector,
+    timeout: Duration,
+}
+
+impl ClusterClient {
+    pub fn new(cluster: &str, clusters: &ClusterManager, timeout: Duration) -> AuthResult<Self> {
+        let lb = clusters
+            .get_cluster(cluster)
+            .ok_or(AuthError::UnknownCluster {
+                name: cluster.to_string(),
+            })?;
+        Ok(Self {
+            cluster: cluster.to_string(),
+            lb,
+            connector: Connector::new(None),
+            timeout,
+        })
+    }
+
+    pub fn cluster(&self) -> &str {
+        &self.cluster
+    }
+
+    /// Sends the request and reads the whole response, `timeout` applies to the whole exchange
+    pub async fn send(
+        &self,
+        req: RequestHeader,
+        body: Option<Bytes>,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        match tokio::time::timeout(self.timeout, self.exchange(req, body)).await {
+            Ok(resp) => resp,
+            Err(_) => Error::e_explain(ErrorType::ReadTimedout, "subrequest timed out"),
+        }
+    }
+
+    async fn exchange(
+        &self,
+        req: RequestHeader,
+        body: Option<Bytes>,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let backend = self.lb.select_backend(&req).ok_or_else(|| {
+            Error::explain(
+                ErrorType::ConnectNoRoute,
+                format!("no backend in cluster {}", self.cluster),
+            )
+        })?;
+        let mut peer = HttpPeer::new(backend, false, String::new());
+        peer.options.total_connection_timeout = Some(self.timeout);
+        peer.options.read_timeout = Some(self.timeout);
+        peer.options.write_timeout = Some(self.timeout);
+
+        let (mut http, _) = self.connector.get_http_session(&peer).await?;
+        http.write_request_header(Box::new(req)).await?;
+        if let Some(body) = body {
+            http.write_request_body(body, true).await?;
+        }
+        http.finish_request_body().await?;
+        http.read_response_header().await?;
+        let resp = http
+            .response_header()
+            .ok_or_else(|| Error::explain(ErrorType::InvalidHTTPHeader, "no response header"))?
+            .clone();
+        let mut body = BytesMut::new();
+        while let Some(chunk) = http.read_response_body().await? {
+            if body.len() + chunk.len() > MAX_BODY_SIZE {
+                return Error::e_explain(ErrorType::ReadError, "response body too large");
+            }
+            body.extend_from_slice(&chunk);
+        }
+        self.connector
+            .release_http_session(http, &peer, Some(Duration::from_secs(60)))
+            .await;
+        Ok((resp, body.freeze()))
+    }
+}
@@ -0,0 +1,99 @@
+use serde_yaml::Error as YamlError;
+use snafu::Snafu;
+
+use crate::config::def::AuthType;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum AuthError {
+    #[snafu(display("Unknown identity: {}", name))]
+    UnknownIdentity { name: String },
+    #[snafu(display(
+        "Failed to parse config of auth {}, error: {:?}",
+        auth_type.as_str(),
+        source
+    ))]
+    YamlErr {
+        source: YamlError,
+        auth_type: AuthType,
+    },
+    #[snafu(display(
+        "None of the allowed identities has credentials for auth {:?}",
+        auth_type
+    ))]
+    NoCredential { auth_type: AuthType },
+    #[snafu(display(
+        "Username {} of identity {} is also used by identity {}",
+        username,
+        identity,
+        other
+    ))]
+    DuplicateUsername {
+        username: String,
+        identity: String,
+        other: String,
+    },
+    #[snafu(display(
+        "Access key {} of identity {} is also used by identity {}",
+        access_key,
+        identity,
+        other
+    ))]
+    DuplicateAccessKey {
+        access_key: String,
+        identity: String,
+        other: String,
+    },
+    #[snafu(display("Invalid password hash of identity: {}", identity))]
+    InvalidPasswordHash { identity: String },
+    #[snafu(display("Failed to read {}, error: {}", path, source))]
+    ReadFile {
+        source: std::io::Error,
+        path: String,
+    },
+    #[snafu(display("Invalid key of identity {}: {}", identity, msg))]
+    InvalidKey { identity: String, msg: String },
+    #[snafu(display("Issuer {} is shared by identities {} and {}", issuer, first, second))]
+    DuplicateIssuer {
+        issuer: String,
+        first: String,
+        second: String,
+    },
+    #[snafu(display("Auth {} requires config", auth_type.as_str()))]
+    LackConfig { auth_type: AuthType },
+    #[snafu(display("Invalid config of auth {}: {}", auth_type.as_str(), msg))]
+    InvalidConfig { auth_type: AuthType, msg: String },
+    #[snafu(display("Unknown cluster: {}", name))]
+    UnknownCluster { name: String },
+    #[snafu(display("Key of identity {} is also used by identity {}", identity, other))]
+    DuplicateKey { identity: String, other: String },
+    #[snafu(display(
+        "Certificate subject {} of identity {} is also used by identity {}",
+        subject,
+        identity,
+        other
+    ))]
+    DuplicateSubject {
+        subject: String,
+        identity: String,
+        other: String,
+    },
+    #[snafu(display(
+        "Certificate SAN {} of identity {} is also used by identity {}",
+        san,
+        identity,
+        other
+    ))]
+    DuplicateSan {
+        san: String,
+        identity: String,
+        other: String,
+    },
+    #[snafu(display("Unsupported algorithm: {}", algorithm))]
+    UnsupportedAlgorithm { algorithm: String },
+    #[snafu(display("Invalid header name {}, error: {}", name, source))]
+    InvalidHeaderName {
+        source: http::header::InvalidHeaderName,
+        name: String,
+    },
+}
@@ -0,0 +1,425 @@
+use std::{
+    num::NonZeroUsize,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, HeaderName, HeaderValue, StatusCode, Version};
+use lru::LruCache;
+use pingora::{http::ResponseHeader, prelude::*};
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    auth::{client::ClusterClient, errors::*, AuthResult},
+    clusters::ClusterManager,
+    config::def::{AuthType, ForwardAuthConfig},
+    core::plugin::{Plugin, PluginCtx},
+    utils::client_ip,
+};
+
+pub fn create_forward_auth(
+    cfg: Option<YamlValue>,
+    clusters: &ClusterManager,
+) -> AuthResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(AuthError::LackConfig {
+        auth_type: AuthType::ForwardAuth,
+    })?;
+    let config: ForwardAuthConfig = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        auth_type: AuthType::ForwardAuth,
+    })?;
+    let parse_headers = |names: &[String]| {
+        names
+            .iter()
+            .map(|name| {
+                HeaderName::from_str(name).context(InvalidHeaderNameSnafu {
+                    name: name.to_string(),
+                })
+            })
+            .collect::<AuthResult<Vec<_>>>()
+    };
+    let cache = match (config.cache_ttl, NonZeroUsize::new(config.cache_size)) {
+        (Some(ttl), Some(size)) => Some(DecisionCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(size)),
+        }),
+        _ => None,
+    };
+    Ok(Box::new(ForwardAuthPlugin {
+        inner: Arc::new(ForwardAuth {
+            client: ClusterClient::new(&config.cluster, clusters, config.timeout)?,
+            path: config.path,
+            headers_to_forward: parse_headers(&config.headers_to_forward)?,
+            upstream_headers: parse_headers(&config.upstream_headers)?,
+            cache,
+        }),
+    }))
+}
+
+/// Outcome of the authorization service
+enum Decision {
+    /// Headers copied to the upstream request
+    Allow(Vec<(HeaderName, HeaderValue)>),
+    /// Response relayed to the client
+    Deny(Box<ResponseHeader>, Bytes),
+}
+
+impl Decision {
+    /// Allows are taken from 2xx responses of the authorization service, other responses deny
+    fn new(resp: ResponseHeader, body: Bytes, upstream_headers: &[HeaderName]) -> Self {
+        if resp.status.is_success() {
+            let headers = upstream_headers
+                .iter()
+                .flat_map(|name| {
+                    resp.headers
+                        .get_all(name)
+                        .iter()
+                        .map(|value| (name.clone(), value.clone()))
+                })
+                .collect();
+            return Decision::Allow(headers);
+        }
+        Decision::Deny(Box::new(resp), body)
+    }
+
+    /// Only allows and explicit 401/403 denies are cached, errors of the authorization service
+    /// would otherwise lock callers out for the whole ttl
+    fn cacheable(&self) -> bool {
+        match self {
+            Decision::Allow(_) => true,
+            Decision::Deny(resp, _) => matches!(
+                resp.status,
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ),
+        }
+    }
+}
+
+/// Replaces the headers named `upstream_headers` of the request with the allowed ones, so that
+/// values sent by the client under the same names never reach the upstream
+fn apply_allow(
+    req: &mut RequestHeader,
+    upstream_headers: &[HeaderName],
+    headers: &[(HeaderName, HeaderValue)],
+) -> Result<()> {
+    for name in upstream_headers {
+        req.remove_header(name);
+    }
+    for (name, value) in headers {
+        req.append_header(name.clone(), value.clone())?;
+    }
+    Ok(())
+}
+
+/// Returns the header of the denial relayed to the client, without the hop-by-hop headers of
+/// the authorization service
+fn deny_response(resp: &ResponseHeader, body: &Bytes) -> Result<ResponseHeader> {
+    let mut resp = resp.clone();
+    resp.set_version(Version::HTTP_11);
+    for name in [
+        header::TRANSFER_ENCODING,
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+    ] {
+        resp.remove_header(&name);
+    }
+    resp.insert_header(header::CONTENT_LENGTH, body.len())?;
+    Ok(resp)
+}
+
+struct DecisionCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, (Instant, Arc<Decision>)>>,
+}
+
+impl DecisionCache {
+    fn get(&self, key: &str) -> Option<Arc<Decision>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((expire_at, decision)) if *expire_at > Instant::now() => Some(decision.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: String, decision: Arc<Decision>) {
+        let expire_at = Instant::now() + self.ttl;
+        self.entries.lock().unwrap().put(key, (expire_at, decision));
+    }
+}
+
+struct ForwardAuth {
+    client: ClusterClient,
+    path: String,
+    headers_to_forward: Vec<HeaderName>,
+    upstream_headers: Vec<HeaderName>,
+    cache: Option<DecisionCache>,
+}
+
+impl ForwardAuth {
+    /// Builds the subrequest, the original request line is passed in `X-Forwarded-*` headers
+    fn build_request(&self, session: &Session) -> Result<RequestHeader> {
+        let req = session.req_header();
+        let mut sub = RequestHeader::build("GET", self.path.as_bytes(), None)?;
+        for name in &self.headers_to_forward {
+            for value in req.headers.get_all(name) {
+                sub.append_header(name.clone(), value.clone())?;
+            }
+        }
+        if let Some(host) = req.headers.get(header::HOST) {
+            sub.insert_header(header::HOST, host.clone())?;
+            sub.insert_header("x-forwarded-host", host.clone())?;
+        }
+        sub.insert_header("x-forwarded-method", req.method.as_str())?;
+        sub.insert_header("x-forwarded-uri", req.uri.to_string())?;
+        if let Some(ip) = client_ip(session) {
+            sub.insert_header("x-forwarded-for", ip.to_string())?;
+        }
+        sub.insert_header(header::CONTENT_LENGTH, "0")?;
+        Ok(sub)
+    }
+
+    /// The decision depends on everything sent to the authorization service
+    fn cache_key(sub: &RequestHeader) -> String {
+        let mut key = String::new();
+        for (name, value) in sub.headers.iter() {
+            key.push_str(name.as_str());
+            key.push(':');
+            key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            key.push('\n');
+        }
+        key
+    }
+
+    async fn authorize(&self, sub: RequestHeader) -> Result<Decision> {
+        let (resp, body) = self.client.send(sub, None).await?;
+        Ok(Decision::new(resp, body, &self.upstream_headers))
+    }
+
+    async fn decide(&self, session: &Session) -> Result<Arc<Decision>> {
+        let sub = self.build_request(session)?;
+        let key = self.cache.as_ref().map(|_| Self::cache_key(&sub));
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(decision) = cache.get(key) {
+                return Ok(decision);
+            }
+        }
+        let decision = Arc::new(self.authorize(sub).await?);
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            if decision.cacheable() {
+                cache.put(key, decision.clone());
+            }
+        }
+        Ok(decision)
+    }
+}
+
+pub struct ForwardAuthPlugin {
+    inner: Arc<ForwardAuth>,
+}
+
+#[async_trait]
+impl Plugin for ForwardAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, _ctx: &mut PluginCtx) -> Result<bool> {
+        let decision = self.inner.decide(session).await.map_err(|e| {
+            Error::because(
+                ErrorType::HTTPStatus(503),
+                format!(
+                    "forward auth to cluster {} failed",
+                    self.inner.client.cluster()
+                ),
+                e,
+            )
+        })?;
+        match decision.as_ref() {
+            Decision::Allow(headers) => {
+                apply_allow(
+                    session.req_header_mut(),
+                    &self.inner.upstream_headers,
+                    headers,
+                )?;
+                Ok(false)
+            }
+            Decision::Deny(resp, body) => {
+                let resp = deny_response(resp, body)?;
+                session.write_response_header(Box::new(resp), false).await?;
+                session
+                    .write_response_body(Some(body.clone()), true)
+                    .await?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::utils::test_session;
+
+    /// Starts an authorization service answering every request with `response`, returns its
+    /// address and the number of requests it received
+    async fn auth_service(response: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let mut read = 0;
+                    loop {
+                        let n = stream.read(&mut buf[read..]).await.unwrap_or(0);
+                        if n == 0 {
+                            return;
+                        }
+                        read += n;
+                        if buf[..read].windows(4).any(|w| w == b"\r\n\r\n") {
+                            break;
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (addr, hits)
+    }
+
+    fn forward_auth(addr: &str, cache_ttl: Option<Duration>) -> ForwardAuth {
+        let cfg = serde_yaml::from_str(&format!(
+            "[{{name: auth, resolver: static, lb_policy: random, config: {{endpoints: [\"{}\"]}}}}]",
+            addr
+        ))
+        .unwrap();
+        let clusters = ClusterManager::new(cfg, &HashMap::new()).unwrap();
+        ForwardAuth {
+            client: ClusterClient::new("auth", &clusters, Duration::from_secs(1)).unwrap(),
+            path: "/check".to_string(),
+            headers_to_forward: vec![header::AUTHORIZATION],
+            upstream_headers: vec![HeaderName::from_static("x-user")],
+            cache: cache_ttl.map(|ttl| DecisionCache {
+                ttl,
+                entries: Mutex::new(LruCache::new(NonZeroUsize::MIN)),
+            }),
+        }
+    }
+
+    const REQUEST: &str = "POST /orders?id=1 HTTP/1.1\r\nHost: example.com\r\n\
+                           Authorization: Bearer t\r\nCookie: c=1\r\nContent-Length: 0\r\n\r\n";
+
+    #[tokio::test]
+    async fn subrequest_carries_the_selected_headers_and_request_line() {
+        let auth = forward_auth("127.0.0.1:1", None);
+        let sub = auth.build_request(&test_session(REQUEST).await).unwrap();
+        let header = |name: &str| sub.headers.get(name).and_then(|v| v.to_str().ok());
+        assert_eq!(sub.method, "GET");
+        assert_eq!(sub.uri, "/check");
+        assert_eq!(header("authorization"), Some("Bearer t"));
+        assert_eq!(header("cookie"), None);
+        assert_eq!(header("x-forwarded-method"), Some("POST"));
+        assert_eq!(header("x-forwarded-uri"), Some("/orders?id=1"));
+        assert_eq!(header("x-forwarded-host"), Some("example.com"));
+        assert_eq!(header("content-length"), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn cache_key_covers_the_subrequest_headers() {
+        let auth = forward_auth("127.0.0.1:1", None);
+        let auth = &auth;
+        let key = |req: &'static str| async move {
+            let session = test_session(req).await;
+            ForwardAuth::cache_key(&auth.build_request(&session).unwrap())
+        };
+        let other_token = REQUEST.replace("Bearer t", "Bearer u").leak();
+        let other_cookie = REQUEST.replace("c=1", "c=2").leak();
+        assert_ne!(key(REQUEST).await, key(other_token).await);
+        // headers which aren't forwarded don't split the cache
+        assert_eq!(key(REQUEST).await, key(other_cookie).await);
+    }
+
+    #[tokio::test]
+    async fn allows_copy_upstream_headers_over_client_ones() {
+        let (addr, _) = auth_service(
+            "HTTP/1.1 200 OK\r\nX-User: alice\r\nX-Other: 1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let auth = forward_auth(&addr, None);
+        let session = test_session(REQUEST).await;
+        let decision = auth.decide(&session).await.unwrap();
+        let Decision::Allow(headers) = decision.as_ref() else {
+            panic!("request denied");
+        };
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-user", "mallory").unwrap();
+        apply_allow(&mut req, &auth.upstream_headers, headers).unwrap();
+        let users: Vec<_> = req.headers.get_all("x-user").iter().collect();
+        assert_eq!(users, ["alice"]);
+        assert!(!req.headers.contains_key("x-other"));
+    }
+
+    #[tokio::test]
+    async fn denies_are_relayed_without_hop_by_hop_headers() {
+        let (addr, _) = auth_service(
+            "HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\nX-Reason: nope\r\n\r\n\
+             4\r\ndeny\r\n0\r\n\r\n",
+        )
+        .await;
+        let auth = forward_auth(&addr, None);
+        let session = test_session(REQUEST).await;
+        let decision = auth.decide(&session).await.unwrap();
+        let Decision::Deny(resp, body) = decision.as_ref() else {
+            panic!("request allowed");
+        };
+        let resp = deny_response(resp, body).unwrap();
+        assert_eq!(resp.status, StatusCode::FORBIDDEN);
+        assert_eq!(body.as_ref(), b"deny");
+        assert!(!resp.headers.contains_key(header::TRANSFER_ENCODING));
+        assert_eq!(resp.headers[header::CONTENT_LENGTH], "4");
+        assert_eq!(resp.headers["x-reason"], "nope");
+    }
+
+    #[tokio::test]
+    async fn only_allows_and_explicit_denies_are_cached() {
+        for (response, cached) in [
+            ("HTTP/1.1 204 No Content\r\n\r\n", true),
+            (
+                "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n",
+                true,
+            ),
+            ("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n", true),
+            ("HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n", false),
+            (
+                "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n",
+                false,
+            ),
+        ] {
+            let (addr, hits) = auth_service(response).await;
+            let auth = forward_auth(&addr, Some(Duration::from_secs(60)));
+            let session = test_session(REQUEST).await;
+            auth.decide(&session).await.unwrap();
+            auth.decide(&session).await.unwrap();
+            let expected = if cached { 1 } else { 2 };
+            assert_eq!(hits.load(Ordering::SeqCst), expected, "{}", response);
+        }
+    }
+}
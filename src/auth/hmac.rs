@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use http::header;
+use log::debug;
+use openssl::{
+    hash::{Hasher, MessageDigest},
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+
+use crate::{
+    auth::{errors::*, parse_config, unauthorized, AuthResult},
+    config::def::{AuthType, Identity},
+    core::plugin::{Plugin, PluginCtx},
+};
+
+const REQUEST_TARGET: &str = "@request-target";
+const DIGEST: &str = "digest";
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct HmacAuthConfig {
+    /// Headers which must be covered by the signature besides `@request-target` and the date
+    signed_headers: Vec<String>,
+    /// Maximum difference between the signed date and the gateway clock
+    #[serde(with = "humantime_serde")]
+    clock_skew: Duration,
+    /// Algorithms clients may sign with
+    algorithms: Vec<String>,
+    /// Requires a signed `Digest` header for requests with a body and verifies it
+    validate_request_body: bool,
+    /// Bodies are buffered until their digest is verified, larger ones are rejected with 413
+    max_body_size: usize,
+    /// Removes the `Authorization` header before proxying to upstream
+    hide_credentials: bool,
+    realm: String,
+}
+
+impl Default for HmacAuthConfig {
+    fn default() -> Self {
+        Self {
+            signed_headers: vec![],
+            clock_skew: Duration::from_secs(300),
+            algorithms: vec!["hmac-sha256".to_string(), "hmac-sha512".to_string()],
+            validate_request_body: false,
+            max_body_size: 1024 * 1024,
+            hide_credentials: false,
+            realm: "penguin".to_string(),
+        }
+    }
+}
+
+pub fn create_hmac_auth(
+    cfg: Option<YamlValue>,
+    identities: &[&Identity],
+) -> AuthResult<Box<dyn Plugin>> {
+    let config: HmacAuthConfig = parse_config(cfg, AuthType::HmacAuth)?;
+    let algorithms = config
+        .algorithms
+        .iter()
+        .map(|name| {
+            message_digest(name).map(|_| name.to_lowercase()).ok_or(
+                AuthError::UnsupportedAlgorithm {
+                    algorithm: name.clone(),
+                },
+            )
+        })
+        .collect::<AuthResult<Vec<_>>>()?;
+    let mut credentials = HashMap::new();
+    for identity in identities {
+        let Some(hmac) = &identity.hmac_auth else {
+            continue;
+        };
+        let secret = PKey::hmac(hmac.secret_key.as_bytes()).map_err(|e| AuthError::InvalidKey {
+            identity: identity.name.clone(),
+            msg: e.to_string(),
+        })?;
+        let other = credentials.insert(
+            hmac.access_key.clone(),
+            Credential {
+                identity: identity.name.clone(),
+                secret,
+            },
+        );
+        if let Some(other) = other {
+            return Err(AuthError::DuplicateAccessKey {
+                access_key: hmac.access_key.clone(),
+                identity: identity.name.clone(),
+                other: other.identity,
+            });
+        }
+    }
+    if credentials.is_empty() {
+        return Err(AuthError::NoCredential {
+            auth_type: AuthType::HmacAuth,
+        });
+    }
+    let mut required = vec![REQUEST_TARGET.to_string()];
+    required.extend(config.signed_headers.iter().map(|h| h.to_lowercase()));
+    Ok(Box::new(HmacAuthPlugin {
+        config: Arc::new(HmacConfig {
+            credentials,
+            challenge: format!(
+                "Signature realm=\"{}\",headers=\"{} date\"",
+                config.realm,
+                required.join(" ")
+            ),
+            required,
+            clock_skew: config.clock_skew,
+            algorithms,
+            validate_request_body: config.validate_request_body,
+            max_body_size: config.max_body_size,
+            hide_credentials: config.hide_credentials,
+        }),
+    }))
+}
+
+fn message_digest(algorithm: &str) -> Option<MessageDigest> {
+    match algorithm.to_lowercase().as_str() {
+        "hmac-sha1" => Some(MessageDigest::sha1()),
+        "hmac-sha256" => Some(MessageDigest::sha256()),
+        "hmac-sha384" => Some(MessageDigest::sha384()),
+        "hmac-sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+struct Credential {
+    identity: String,
+    secret: PKey<Private>,
+}
+
+struct HmacConfig {
+    /// Credentials indexed by access key
+    credentials: HashMap<String, Credential>,
+    challenge: String,
+    /// Lowercased names which must be listed in `headers` of the signature
+    required: Vec<String>,
+    clock_skew: Duration,
+    algorithms: Vec<String>,
+    validate_request_body: bool,
+    max_body_size: usize,
+    hide_credentials: bool,
+}
+
+/// Parameters of `Authorization: Signature keyId="..",algorithm="..",headers="..",signature=".."`
+struct SignatureParams {
+    key_id: String,
+    algorithm: String,
+    headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+impl SignatureParams {
+    fn parse(value: &str) -> Option<Self> {
+        let (scheme, params) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("signature") {
+            return None;
+        }
+        let mut key_id = None;
+        let mut algorithm = None;
+        let mut headers = None;
+        let mut signature = None;
+        for param in params.split(',') {
+            let (k, v) = param.split_once('=')?;
+            let v = v.trim().trim_matches('"');
+            match k.trim() {
+                "keyId" => key_id = Some(v.to_string()),
+                "algorithm" => algorithm = Some(v.to_lowercase()),
+                "headers" => {
+                    headers = Some(v.split_whitespace().map(|h| h.to_lowercase()).collect())
+                }
+                "signature" => signature = Some(STANDARD.decode(v).ok()?),
+                _ => {}
+            }
+        }
+        Some(Self {
+            key_id: key_id?,
+            algorithm: algorithm?,
+            headers: headers?,
+            signature: signature?,
+        })
+    }
+}
+
+/// Digest of the request body announced by the client, the hasher fed with the body so far and
+/// the body held back until the digest is verified
+#[derive(Clone)]
+struct BodyDigest {
+    expected: Vec<u8>,
+    hasher: Hasher,
+    body: BytesMut,
+}
+
+impl BodyDigest {
+    /// Parses `Digest: SHA-256=<base64>`, `SHA-512` is supported as well
+    fn parse(value: &str) -> Option<Self> {
+        let (algorithm, digest) = value.trim().split_once('=')?;
+        let md = match algorithm.to_uppercase().as_str() {
+            "SHA-256" => MessageDigest::sha256(),
+            "SHA-512" => MessageDigest::sha512(),
+            _ => return None,
+        };
+        Some(Self {
+            expected: STANDARD.decode(digest.trim()).ok()?,
+            hasher: Hasher::new(md).ok()?,
+            body: BytesMut::new(),
+        })
+    }
+}
+
+impl HmacConfig {
+    /// Verifies the signature of the request and returns the identity of the access key
+    fn verify(&self, req: &RequestHeader) -> Result<&str, &'static str> {
+        let authorization = req
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or("missing signature")?;
+        let params = SignatureParams::parse(authorization).ok_or("malformed signature")?;
+        if !self.algorithms.contains(&params.algorithm) {
+            return Err("algorithm not allowed");
+        }
+        let md = message_digest(&params.algorithm).ok_or("algorithm not allowed")?;
+        if let Some(missing) = self.required.iter().find(|h| !params.headers.contains(h)) {
+            debug!("hmac signature doesn't cover {}", missing);
+            return Err("required header not signed");
+        }
+        if self.validate_request_body
+            && req.headers.contains_key(DIGEST)
+            && !params.headers.iter().any(|h| h == DIGEST)
+        {
+            return Err("digest not signed");
+        }
+
+        // the date is signed either as `date` or as `x-date` for clients which can't set `Date`
+        let date = ["date", "x-date"]
+            .into_iter()
+            .find(|h| params.headers.iter().any(|signed| signed == h))
+            .and_then(|h| req.headers.get(h))
+            .and_then(|v| v.to_str().ok())
+            .ok_or("date not signed")?;
+        let date = httpdate::parse_http_date(date).map_err(|_| "malformed date")?;
+        let now = SystemTime::now();
+        let skew = now
+            .duration_since(date)
+            .or_else(|_| date.duration_since(now))
+            .unwrap_or_default();
+        if skew > self.clock_skew {
+            return Err("clock skew too large");
+        }
+
+        let credential = self
+            .credentials
+            .get(&params.key_id)
+            .ok_or("unknown access key")?;
+        let signing_string = signing_string(req, &params.headers).ok_or("signed header missing")?;
+        let mut signer = Signer::new(md, &credential.secret).map_err(|_| "invalid key")?;
+        let mac = signer
+            .sign_oneshot_to_vec(signing_string.as_bytes())
+            .map_err(|_| "invalid key")?;
+        if mac.len() != params.signature.len() || !openssl::memcmp::eq(&mac, &params.signature) {
+            return Err("invalid signature");
+        }
+        Ok(&credential.identity)
+    }
+}
+
+/// Builds the string to sign, one `name: value` line per signed header in the order of `headers`
+fn signing_string(req: &RequestHeader, headers: &[String]) -> Option<String> {
+    let mut lines = Vec::with_capacity(headers.len());
+    for name in headers {
+        if name == REQUEST_TARGET {
+            let target = req
+                .uri
+                .path_and_query()
+                .map_or(req.uri.path(), |pq| pq.as_str());
+            lines.push(format!(
+                "{}: {} {}",
+                REQUEST_TARGET,
+                req.method.as_str().to_lowercase(),
+                target
+            ));
+            continue;
+        }
+        let values = req
+            .headers
+            .get_all(name.as_str())
+            .iter()
+            .map(|v| v.to_str().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if values.is_empty() {
+            return None;
+        }
+        lines.push(format!("{}: {}", name, values.join(", ")));
+    }
+    Some(lines.join("\n"))
+}
+
+fn has_body(req: &RequestHeader) -> bool {
+    req.headers.contains_key(header::TRANSFER_ENCODING)
+        || req
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim() != "0")
+}
+
+pub struct HmacAuthPlugin {
+    config: Arc<HmacConfig>,
+}
+
+#[async_trait]
+impl Plugin for HmacAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let req = session.req_header();
+        let result = self.config.verify(req).and_then(|identity| {
+            if !self.config.validate_request_body {
+                return Ok(identity);
+            }
+            match req.headers.get(DIGEST).and_then(|v| v.to_str().ok()) {
+                Some(digest) => {
+                    let digest = BodyDigest::parse(digest).ok_or("malformed digest")?;
+                    ctx.extensions.insert(digest);
+                    Ok(identity)
+                }
+                None if has_body(req) => Err("missing digest"),
+                None => Ok(identity),
+            }
+        });
+        match result {
+            Ok(identity) => {
+                ctx.identity = Some(identity.to_string());
+                Ok(false)
+            }
+            Err(reason) => {
+                debug!("hmac rejected: {}", reason);
+                unauthorized(session, self.config.challenge.clone()).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Feeds the body into the digest and holds it back, it's only forwarded once the digest
+    /// matches
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        let Some(digest) = ctx.extensions.get_mut::<BodyDigest>() else {
+            return Ok(());
+        };
+        if let Some(chunk) = body.take() {
+            if digest.body.len() + chunk.len() > self.config.max_body_size {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(413),
+                    "request body exceeds max_body_size",
+                );
+            }
+            digest
+                .hasher
+                .update(&chunk)
+                .or_err(InternalError, "failed to hash request body")?;
+            digest.body.extend_from_slice(&chunk);
+        }
+        // an empty chunk holds the body back, None would end it
+        *body = Some(Bytes::new());
+        if end_of_stream {
+            let actual = digest
+                .hasher
+                .finish()
+                .or_err(InternalError, "failed to hash request body")?;
+            if *actual != digest.expected[..] {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(401),
+                    "request body doesn't match the digest",
+                );
+            }
+            *body = Some(digest.body.split().freeze());
+        }
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if self.config.hide_credentials {
+            upstream_request.remove_header(&header::AUTHORIZATION);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/orders?id=1", None).unwrap();
+        req.insert_header("date", "Tue, 07 Jun 2022 20:51:35 GMT")
+            .unwrap();
+        req.append_header("x-tenant", "a").unwrap();
+        req.append_header("x-tenant", "b").unwrap();
+        req
+    }
+
+    #[test]
+    fn signing_string_lists_headers_in_signed_order() {
+        let headers = ["x-tenant", REQUEST_TARGET, "date"].map(String::from);
+        assert_eq!(
+            signing_string(&request(), &headers).unwrap(),
+            "x-tenant: a, b\n\
+             @request-target: post /orders?id=1\n\
+             date: Tue, 07 Jun 2022 20:51:35 GMT"
+        );
+    }
+
+    #[test]
+    fn signing_string_requires_signed_headers() {
+        let headers = [REQUEST_TARGET, "digest"].map(String::from);
+        assert!(signing_string(&request(), &headers).is_none());
+    }
+
+    #[test]
+    fn access_keys_are_unique_across_identities() {
+        let identities: Vec<Identity> = serde_yaml::from_str(
+            r#"
+            - {name: a, hmac_auth: {access_key: k, secret_key: x}}
+            - {name: b, hmac_auth: {access_key: k, secret_key: y}}
+            "#,
+        )
+        .unwrap();
+        let identities: Vec<_> = identities.iter().collect();
+        assert!(matches!(
+            create_hmac_auth(None, &identities),
+            Err(AuthError::DuplicateAccessKey { .. })
+        ));
+    }
+
+    #[test]
+    fn digest_is_parsed_by_algorithm() {
+        let digest = BodyDigest::parse("SHA-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=");
+        assert_eq!(digest.unwrap().expected.len(), 32);
+        assert!(BodyDigest::parse("MD5=rL0Y20zC+Fzt72VPzMSk2A==").is_none());
+        assert!(BodyDigest::parse("SHA-256=not base64").is_none());
+    }
+}
@@ -0,0 +1,726 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use base64::{
+    alphabet::URL_SAFE,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use http::{header, HeaderName, HeaderValue};
+use log::debug;
+use openssl::{
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, Private, Public},
+    rsa::Rsa,
+    sign::{Signer, Verifier},
+};
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    auth::{errors::*, parse_config, unauthorized, AuthResult},
+    config::def::{AuthType, Identity},
+    core::plugin::{Plugin, PluginCtx},
+    utils::{query_param, remove_query_param},
+};
+
+/// base64url engine accepting both padded and unpadded input, JWKS files in the wild use both
+pub(super) const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct JwtAuthConfig {
+    /// Header carrying the token, the `Bearer` scheme is optional
+    header: Option<String>,
+    /// Query parameter carrying the token
+    query: Option<String>,
+    /// Cookie carrying the token
+    cookie: Option<String>,
+    /// If set, the `aud` claim must contain at least one of them
+    audience: Vec<String>,
+    /// Tolerance when checking `exp` and `nbf`
+    #[serde(with = "humantime_serde")]
+    clock_skew: Duration,
+    /// Claims forwarded to upstream, claim name -> header name
+    forward_claims: HashMap<String, String>,
+    /// Removes the token from the header and query parameter before proxying to upstream
+    hide_credentials: bool,
+    realm: String,
+}
+
+impl Default for JwtAuthConfig {
+    fn default() -> Self {
+        Self {
+            header: Some(header::AUTHORIZATION.to_string()),
+            query: None,
+            cookie: None,
+            audience: vec![],
+            clock_skew: Duration::from_secs(60),
+            forward_claims: HashMap::new(),
+            hide_credentials: false,
+            realm: "penguin".to_string(),
+        }
+    }
+}
+
+pub fn create_jwt_auth(
+    cfg: Option<YamlValue>,
+    identities: &[&Identity],
+) -> AuthResult<Box<dyn Plugin>> {
+    let config: JwtAuthConfig = parse_config(cfg, AuthType::JwtAuth)?;
+    let mut issuers: HashMap<String, Issuer> = HashMap::new();
+    for identity in identities {
+        let Some(jwt) = &identity.jwt_auth else {
+            continue;
+        };
+        let keys = KeySet::load(
+            jwt.secret.as_deref(),
+            jwt.public_key.as_deref(),
+            jwt.jwks.as_deref(),
+            |msg| AuthError::InvalidKey {
+                identity: identity.name.clone(),
+                msg,
+            },
+        )?;
+        if let Some(other) = issuers.get(&jwt.issuer) {
+            return Err(AuthError::DuplicateIssuer {
+                issuer: jwt.issuer.clone(),
+                first: other.identity.clone(),
+                second: identity.name.clone(),
+            });
+        }
+        issuers.insert(
+            jwt.issuer.clone(),
+            Issuer {
+                identity: identity.name.clone(),
+                keys,
+            },
+        );
+    }
+    if issuers.is_empty() {
+        return Err(AuthError::NoCredential {
+            auth_type: AuthType::JwtAuth,
+        });
+    }
+    let parse_header = |name: &str| {
+        HeaderName::from_str(name).context(InvalidHeaderNameSnafu {
+            name: name.to_string(),
+        })
+    };
+    let forward_claims = config
+        .forward_claims
+        .iter()
+        .map(|(claim, name)| Ok((claim.clone(), parse_header(name)?)))
+        .collect::<AuthResult<Vec<_>>>()?;
+    let header = config.header.as_deref().map(parse_header).transpose()?;
+    Ok(Box::new(JwtAuthPlugin {
+        config: Arc::new(JwtConfig {
+            issuers,
+            header,
+            query: config.query,
+            cookie: config.cookie,
+            audience: config.audience,
+            clock_skew: config.clock_skew.as_secs(),
+            forward_claims,
+            hide_credentials: config.hide_credentials,
+            realm: config.realm,
+        }),
+    }))
+}
+
+/// Verification keys of an issuer, a key loaded from JWKS may carry a `kid`
+pub(super) struct KeySet(Vec<(Option<String>, VerifyKey)>);
+
+impl KeySet {
+    /// Loads the HS256 `secret`, the PEM file `public_key` and the JWKS file `jwks`, at least one
+    /// of them is required
+    pub(super) fn load(
+        secret: Option<&str>,
+        public_key: Option<&str>,
+        jwks: Option<&str>,
+        invalid: impl Fn(String) -> AuthError,
+    ) -> AuthResult<Self> {
+        let mut keys = vec![];
+        if let Some(secret) = secret {
+            let key = PKey::hmac(secret.as_bytes()).map_err(|e| invalid(e.to_string()))?;
+            keys.push((None, VerifyKey::Hmac(key)));
+        }
+        if let Some(path) = public_key {
+            let pem = std::fs::read(path).context(ReadFileSnafu { path })?;
+            let key = PKey::public_key_from_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+            keys.push((None, VerifyKey::from_public(key).map_err(&invalid)?));
+        }
+        if let Some(path) = jwks {
+            let content = std::fs::read(path).context(ReadFileSnafu { path })?;
+            let jwks: Jwks = serde_json::from_slice(&content)
+                .map_err(|e| invalid(format!("malformed jwks {}: {}", path, e)))?;
+            for jwk in jwks.keys {
+                if jwk.key_use.as_deref() == Some("enc") {
+                    continue;
+                }
+                let kid = jwk.kid.clone();
+                keys.push((kid, jwk.into_key().map_err(&invalid)?));
+            }
+        }
+        if keys.is_empty() {
+            return Err(invalid(
+                "one of secret, public_key and jwks is required".to_string(),
+            ));
+        }
+        Ok(Self(keys))
+    }
+
+    /// Returns whether a key matching the `kid` and `alg` of the token verifies its signature
+    pub(super) fn verify(&self, token: &Token) -> bool {
+        self.0
+            .iter()
+            .filter(|(kid, _)| {
+                token.header.kid.is_none() || kid.is_none() || *kid == token.header.kid
+            })
+            .filter(|(_, key)| key.algorithm() == token.header.alg)
+            .any(|(_, key)| key.verify(token.message.as_bytes(), &token.signature))
+    }
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    k: Option<String>,
+}
+
+impl Jwk {
+    fn into_key(self) -> Result<VerifyKey, String> {
+        let kid = self.kid.as_deref().unwrap_or("-");
+        let param = |v: &Option<String>, name: &str| -> Result<Vec<u8>, String> {
+            let v = v
+                .as_ref()
+                .ok_or_else(|| format!("jwk {} lacks parameter {}", kid, name))?;
+            BASE64_URL
+                .decode(v)
+                .map_err(|e| format!("jwk {} has malformed parameter {}: {}", kid, name, e))
+        };
+        let bn = |v: &Option<String>, name: &str| -> Result<BigNum, String> {
+            BigNum::from_slice(&param(v, name)?).map_err(|e| e.to_string())
+        };
+        match self.kty.as_str() {
+            "oct" => PKey::hmac(&param(&self.k, "k")?)
+                .map(VerifyKey::Hmac)
+                .map_err(|e| e.to_string()),
+            "RSA" => {
+                let rsa = Rsa::from_public_components(bn(&self.n, "n")?, bn(&self.e, "e")?)
+                    .map_err(|e| e.to_string())?;
+                PKey::from_rsa(rsa)
+                    .map(VerifyKey::Rsa)
+                    .map_err(|e| e.to_string())
+            }
+            "EC" => {
+                if self.crv.as_deref() != Some("P-256") {
+                    return Err(format!("jwk {} uses an unsupported curve", kid));
+                }
+                let group =
+                    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|e| e.to_string())?;
+                let (x, y) = (bn(&self.x, "x")?, bn(&self.y, "y")?);
+                let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+                    .map_err(|e| e.to_string())?;
+                PKey::from_ec_key(ec)
+                    .map(VerifyKey::Ec)
+                    .map_err(|e| e.to_string())
+            }
+            kty => Err(format!("jwk {} has unsupported kty {}", kid, kty)),
+        }
+    }
+}
+
+/// A verification key, each type of key accepts exactly one algorithm so that a token can't pick
+/// a weaker one
+enum VerifyKey {
+    /// HS256
+    Hmac(PKey<Private>),
+    /// RS256
+    Rsa(PKey<Public>),
+    /// ES256
+    Ec(PKey<Public>),
+}
+
+impl VerifyKey {
+    fn from_public(key: PKey<Public>) -> Result<Self, String> {
+        match key.id() {
+            Id::RSA => Ok(VerifyKey::Rsa(key)),
+            Id::EC => {
+                let curve = key.ec_key().ok().and_then(|ec| ec.group().curve_name());
+                if curve != Some(Nid::X9_62_PRIME256V1) {
+                    return Err("only P-256 EC keys are supported".to_string());
+                }
+                Ok(VerifyKey::Ec(key))
+            }
+            _ => Err("only RSA and EC public keys are supported".to_string()),
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            VerifyKey::Hmac(_) => "HS256",
+            VerifyKey::Rsa(_) => "RS256",
+            VerifyKey::Ec(_) => "ES256",
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.try_verify(message, signature).unwrap_or(false)
+    }
+
+    fn try_verify(
+        &self,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, openssl::error::ErrorStack> {
+        match self {
+            VerifyKey::Hmac(key) => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+                signer.update(message)?;
+                let mac = signer.sign_to_vec()?;
+                Ok(mac.len() == signature.len() && openssl::memcmp::eq(&mac, signature))
+            }
+            VerifyKey::Rsa(key) => {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+                verifier.update(message)?;
+                verifier.verify(signature)
+            }
+            VerifyKey::Ec(key) => {
+                // JWS encodes ECDSA signatures as r || s instead of DER
+                if signature.len() != 64 {
+                    return Ok(false);
+                }
+                let sig = EcdsaSig::from_private_components(
+                    BigNum::from_slice(&signature[..32])?,
+                    BigNum::from_slice(&signature[32..])?,
+                )?;
+                let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+                verifier.update(message)?;
+                verifier.verify(&sig.to_der()?)
+            }
+        }
+    }
+}
+
+struct Issuer {
+    identity: String,
+    keys: KeySet,
+}
+
+struct JwtConfig {
+    /// Issuers of the allowed identities, indexed by `iss`
+    issuers: HashMap<String, Issuer>,
+    header: Option<HeaderName>,
+    query: Option<String>,
+    cookie: Option<String>,
+    audience: Vec<String>,
+    clock_skew: u64,
+    forward_claims: Vec<(String, HeaderName)>,
+    hide_credentials: bool,
+    realm: String,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// A token split into its decoded parts, its signature isn't verified yet
+pub(super) struct Token<'a> {
+    header: JwtHeader,
+    pub(super) claims: Map<String, Value>,
+    /// Signed part of the token, the encoded header and claims
+    message: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Token<'a> {
+    pub(super) fn decode(token: &'a str) -> Result<Self, &'static str> {
+        let (message, signature) = token.rsplit_once('.').ok_or("malformed token")?;
+        let (header, claims) = message.split_once('.').ok_or("malformed token")?;
+        Ok(Self {
+            header: decode_json(header).ok_or("malformed header")?,
+            claims: decode_json(claims).ok_or("malformed claims")?,
+            message,
+            signature: BASE64_URL
+                .decode(signature)
+                .map_err(|_| "malformed signature")?,
+        })
+    }
+}
+
+impl JwtConfig {
+    /// Looks up the token in the header, query parameter and cookie in order
+    fn extract_token(&self, session: &Session, ctx: &PluginCtx) -> Option<String> {
+        let req = session.req_header();
+        if let Some(name) = &self.header {
+            if let Some(value) = req.headers.get(name).and_then(|v| v.to_str().ok()) {
+                let token = match value.trim().split_once(' ') {
+                    Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token,
+                    _ => value,
+                };
+                return Some(token.trim().to_string());
+            }
+        }
+        if let Some(token) = self.query.as_ref().and_then(|name| query_param(req, name)) {
+            return Some(token.to_string());
+        }
+        self.cookie
+            .as_ref()
+            .and_then(|name| ctx.cookie(req, name))
+            .map(|token| token.to_string())
+    }
+
+    /// Verifies the token at `now`, in seconds since the epoch, and returns the identity of its
+    /// issuer along with the claims
+    fn verify(&self, token: &str, now: u64) -> Result<(&str, Map<String, Value>), &'static str> {
+        let token = Token::decode(token)?;
+        let issuer = token
+            .claims
+            .get("iss")
+            .and_then(|v| v.as_str())
+            .and_then(|iss| self.issuers.get(iss))
+            .ok_or("unknown issuer")?;
+        if !issuer.keys.verify(&token) {
+            return Err("invalid signature");
+        }
+        let claims = token.claims;
+
+        if let Some(exp) = claims.get("exp") {
+            let exp = numeric_date(exp).ok_or("malformed exp")?;
+            if now > exp.saturating_add(self.clock_skew) {
+                return Err("token expired");
+            }
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            let nbf = numeric_date(nbf).ok_or("malformed nbf")?;
+            if now.saturating_add(self.clock_skew) < nbf {
+                return Err("token not yet valid");
+            }
+        }
+        if !self.audience.is_empty() {
+            let matched = match claims.get("aud") {
+                Some(Value::String(aud)) => self.audience.contains(aud),
+                Some(Value::Array(auds)) => auds
+                    .iter()
+                    .filter_map(|aud| aud.as_str())
+                    .any(|aud| self.audience.iter().any(|a| a == aud)),
+                _ => false,
+            };
+            if !matched {
+                return Err("audience mismatch");
+            }
+        }
+        Ok((&issuer.identity, claims))
+    }
+}
+
+/// Reads a NumericDate claim, which may have a fractional part, truncated to whole seconds
+pub(super) fn numeric_date(value: &Value) -> Option<u64> {
+    value.as_u64().or_else(|| {
+        value
+            .as_f64()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(|secs| secs as u64)
+    })
+}
+
+/// Returns the current time in seconds since the epoch
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub(super) fn decode_json<T: serde::de::DeserializeOwned>(segment: &str) -> Option<T> {
+    serde_json::from_slice(&BASE64_URL.decode(segment).ok()?).ok()
+}
+
+/// Renders a claim as a header value, arrays of strings are joined by `,`
+pub(super) fn claim_to_header(claim: &Value) -> Option<HeaderValue> {
+    let value = match claim {
+        Value::String(s) => s.clone(),
+        Value::Array(items) if items.iter().all(|v| v.is_string()) => items
+            .iter()
+            .filter_map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::Null => return None,
+        other => other.to_string(),
+    };
+    HeaderValue::from_str(&value).ok()
+}
+
+pub struct JwtAuthPlugin {
+    config: Arc<JwtConfig>,
+}
+
+#[async_trait]
+impl Plugin for JwtAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let Some(token) = self.config.extract_token(session, ctx) else {
+            unauthorized(session, format!("Bearer realm=\"{}\"", self.config.realm)).await?;
+            return Ok(true);
+        };
+        match self.config.verify(&token, now()) {
+            Ok((identity, claims)) => {
+                ctx.identity = Some(identity.to_string());
+                // forwarded claims are set on the downstream request so that values sent by the
+                // client under the same names never reach the upstream
+                let req = session.req_header_mut();
+                for (claim, name) in &self.config.forward_claims {
+                    req.remove_header(name);
+                    if let Some(value) = claims.get(claim).and_then(claim_to_header) {
+                        req.insert_header(name.clone(), value)?;
+                    }
+                }
+                Ok(false)
+            }
+            Err(reason) => {
+                debug!("jwt rejected: {}", reason);
+                let challenge = format!(
+                    "Bearer realm=\"{}\", error=\"invalid_token\"",
+                    self.config.realm
+                );
+                unauthorized(session, challenge).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if self.config.hide_credentials {
+            if let Some(name) = &self.config.header {
+                upstream_request.remove_header(name);
+            }
+            if let Some(name) = &self.config.query {
+                remove_query_param(upstream_request, name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{ec::EcKey, pkey::HasPrivate};
+    use serde_json::json;
+
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn encode(value: &Value) -> String {
+        BASE64_URL.encode(value.to_string())
+    }
+
+    /// Builds a token signed with `key`, `alg` is only written in the header
+    fn token<T: HasPrivate>(alg: &str, claims: Value, key: &PKey<T>) -> String {
+        let message = format!(
+            "{}.{}",
+            encode(&json!({"alg": alg, "typ": "JWT"})),
+            encode(&claims)
+        );
+        let signature = match key.id() {
+            Id::EC => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+                let der = signer.sign_oneshot_to_vec(message.as_bytes()).unwrap();
+                let sig = EcdsaSig::from_der(&der).unwrap();
+                let mut signature = sig.r().to_vec_padded(32).unwrap();
+                signature.extend(sig.s().to_vec_padded(32).unwrap());
+                signature
+            }
+            _ => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+                signer.sign_oneshot_to_vec(message.as_bytes()).unwrap()
+            }
+        };
+        format!("{}.{}", message, BASE64_URL.encode(signature))
+    }
+
+    fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn public(key: &PKey<Private>) -> VerifyKey {
+        let pem = key.public_key_to_pem().unwrap();
+        VerifyKey::from_public(PKey::public_key_from_pem(&pem).unwrap()).unwrap()
+    }
+
+    fn config(keys: KeySet, audience: &[&str]) -> JwtConfig {
+        JwtConfig {
+            issuers: HashMap::from([(
+                "iss1".to_string(),
+                Issuer {
+                    identity: "alice".to_string(),
+                    keys,
+                },
+            )]),
+            header: Some(header::AUTHORIZATION),
+            query: None,
+            cookie: None,
+            audience: audience.iter().map(|a| a.to_string()).collect(),
+            clock_skew: 60,
+            forward_claims: vec![],
+            hide_credentials: false,
+            realm: "penguin".to_string(),
+        }
+    }
+
+    fn claims() -> Value {
+        json!({"iss": "iss1", "sub": "u1"})
+    }
+
+    #[test]
+    fn tokens_of_each_algorithm_are_verified() {
+        let secret = PKey::hmac(b"secret").unwrap();
+        let rsa = rsa_key();
+        let ec = ec_key();
+        let keys = KeySet(vec![
+            (None, VerifyKey::Hmac(secret.clone())),
+            (None, public(&rsa)),
+            (None, public(&ec)),
+        ]);
+        let config = config(keys, &[]);
+        for token in [
+            token("HS256", claims(), &secret),
+            token("RS256", claims(), &rsa),
+            token("ES256", claims(), &ec),
+        ] {
+            let (identity, _) = config.verify(&token, NOW).unwrap();
+            assert_eq!(identity, "alice");
+        }
+        let forged = token("HS256", claims(), &PKey::hmac(b"other").unwrap());
+        assert_eq!(config.verify(&forged, NOW), Err("invalid signature"));
+        let unknown = token("HS256", json!({"iss": "iss2"}), &secret);
+        assert_eq!(config.verify(&unknown, NOW), Err("unknown issuer"));
+    }
+
+    #[test]
+    fn algorithms_are_pinned_to_the_key_type() {
+        let rsa = rsa_key();
+        let pem = rsa.public_key_to_pem().unwrap();
+        let config = config(KeySet(vec![(None, public(&rsa))]), &[]);
+        // the public key used as an HMAC secret
+        let confused = token("HS256", claims(), &PKey::hmac(&pem).unwrap());
+        assert_eq!(config.verify(&confused, NOW), Err("invalid signature"));
+        let unsigned = format!("{}.{}.", encode(&json!({"alg": "none"})), encode(&claims()));
+        assert_eq!(config.verify(&unsigned, NOW), Err("invalid signature"));
+    }
+
+    #[test]
+    fn jwks_encryption_keys_are_skipped() {
+        let rsa = rsa_key().rsa().unwrap();
+        let jwk = |key_use: &str| {
+            json!({
+                "kty": "RSA",
+                "use": key_use,
+                "n": BASE64_URL.encode(rsa.n().to_vec()),
+                "e": BASE64_URL.encode(rsa.e().to_vec()),
+            })
+        };
+        let oct = json!({"kty": "oct", "k": BASE64_URL.encode("secret")});
+        let path = std::env::temp_dir().join(format!("penguin-jwks-{}.json", std::process::id()));
+        let load = |keys: Value| {
+            std::fs::write(&path, json!({ "keys": keys }).to_string()).unwrap();
+            let keys = KeySet::load(None, None, path.to_str(), |msg| AuthError::InvalidKey {
+                identity: "alice".to_string(),
+                msg,
+            });
+            keys.map(|keys| {
+                keys.0
+                    .iter()
+                    .map(|(_, key)| key.algorithm())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(load(json!([jwk("enc"), oct])).unwrap(), ["HS256"]);
+        assert_eq!(load(json!([jwk("sig")])).unwrap(), ["RS256"]);
+        assert!(load(json!([jwk("enc")])).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn time_claims_allow_the_clock_skew() {
+        let secret = PKey::hmac(b"secret").unwrap();
+        let config = config(KeySet(vec![(None, VerifyKey::Hmac(secret.clone()))]), &[]);
+        let verify = |claims: Value| {
+            let mut all = self::claims();
+            all.as_object_mut()
+                .unwrap()
+                .extend(claims.as_object().unwrap().clone());
+            config
+                .verify(&token("HS256", all, &secret), NOW)
+                .map(|_| ())
+        };
+        assert!(verify(json!({"exp": NOW - 60})).is_ok());
+        assert_eq!(verify(json!({"exp": NOW - 61})), Err("token expired"));
+        assert!(verify(json!({"exp": NOW as f64 + 0.5})).is_ok());
+        assert_eq!(verify(json!({"exp": "soon"})), Err("malformed exp"));
+        assert!(verify(json!({"nbf": NOW + 60})).is_ok());
+        assert_eq!(verify(json!({"nbf": NOW + 61})), Err("token not yet valid"));
+        assert!(verify(json!({"nbf": NOW as f64 - 0.5})).is_ok());
+    }
+
+    #[test]
+    fn audience_must_match_one_of_the_configured() {
+        let secret = PKey::hmac(b"secret").unwrap();
+        let config = config(
+            KeySet(vec![(None, VerifyKey::Hmac(secret.clone()))]),
+            &["api", "web"],
+        );
+        let verify = |aud: Value| {
+            let claims = json!({"iss": "iss1", "aud": aud});
+            config
+                .verify(&token("HS256", claims, &secret), NOW)
+                .map(|_| ())
+        };
+        assert!(verify(json!("web")).is_ok());
+        assert!(verify(json!(["other", "api"])).is_ok());
+        assert_eq!(verify(json!("other")), Err("audience mismatch"));
+        assert_eq!(verify(Value::Null), Err("audience mismatch"));
+    }
+}
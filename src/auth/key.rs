@@ -0,0 +1,206 @@
+use std::{borrow::Cow, collections::HashMap, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use http::HeaderName;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    auth::{errors::*, parse_config, unauthorized, AuthResult},
+    config::def::{AuthType, Identity},
+    core::plugin::{Plugin, PluginCtx},
+    utils::{query_param, remove_query_param},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct KeyAuthConfig {
+    /// Header carrying the key
+    header: Option<String>,
+    /// Query parameter carrying the key, the header takes precedence
+    query: Option<String>,
+}
+
+impl Default for KeyAuthConfig {
+    fn default() -> Self {
+        Self {
+            header: Some("x-api-key".to_string()),
+            query: None,
+        }
+    }
+}
+
+pub fn create_key_auth(
+    cfg: Option<YamlValue>,
+    identities: &[&Identity],
+) -> AuthResult<Box<dyn Plugin>> {
+    let config: KeyAuthConfig = parse_config(cfg, AuthType::KeyAuth)?;
+    Ok(Box::new(KeyAuthPlugin::new(config, identities)?))
+}
+
+impl KeyAuthPlugin {
+    fn new(config: KeyAuthConfig, identities: &[&Identity]) -> AuthResult<Self> {
+        let mut keys: HashMap<String, String> = HashMap::new();
+        for identity in identities {
+            let Some(key_auth) = &identity.key_auth else {
+                continue;
+            };
+            for key in &key_auth.keys {
+                let other = keys.insert(key.clone(), identity.name.clone());
+                if let Some(other) = other.filter(|other| *other != identity.name) {
+                    return Err(AuthError::DuplicateKey {
+                        identity: identity.name.clone(),
+                        other,
+                    });
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Err(AuthError::NoCredential {
+                auth_type: AuthType::KeyAuth,
+            });
+        }
+        let header = config
+            .header
+            .map(|name| HeaderName::from_str(&name).context(InvalidHeaderNameSnafu { name }))
+            .transpose()?;
+        Ok(Self {
+            config: Arc::new(KeyConfig {
+                challenge: "Key realm=\"penguin\"".to_string(),
+                keys,
+                header,
+                query: config.query,
+            }),
+        })
+    }
+}
+
+struct KeyConfig {
+    /// Names of the identities indexed by their keys
+    keys: HashMap<String, String>,
+    header: Option<HeaderName>,
+    query: Option<String>,
+    challenge: String,
+}
+
+impl KeyConfig {
+    /// Looks up the key in the header, then in the query parameter
+    fn resolve(&self, req: &RequestHeader) -> Option<&str> {
+        let key = self
+            .header
+            .as_ref()
+            .and_then(|name| req.headers.get(name))
+            .and_then(|v| v.to_str().ok())
+            .map(Cow::Borrowed)
+            .or_else(|| self.query.as_ref().and_then(|name| query_param(req, name)))?;
+        self.keys.get(key.trim()).map(|identity| identity.as_str())
+    }
+}
+
+pub struct KeyAuthPlugin {
+    config: Arc<KeyConfig>,
+}
+
+#[async_trait]
+impl Plugin for KeyAuthPlugin {
+    /// The key is removed from the request once resolved, so that it never reaches the logs,
+    /// later plugins or the upstream
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let Some(identity) = self.config.resolve(session.req_header()) else {
+            unauthorized(session, self.config.challenge.clone()).await?;
+            return Ok(true);
+        };
+        ctx.identity = Some(identity.to_string());
+        let req = session.req_header_mut();
+        if let Some(name) = &self.config.header {
+            req.remove_header(name);
+        }
+        if let Some(name) = &self.config.query {
+            remove_query_param(req, name);
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_session;
+
+    fn identities(yaml: &str) -> Vec<Identity> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn plugin(cfg: &str) -> KeyAuthPlugin {
+        let identities = identities(
+            r#"
+            - {name: alice, key_auth: {keys: ["a+b/c=="]}}
+            - {name: bob, key_auth: {keys: [bob-key]}}
+            "#,
+        );
+        let identities: Vec<_> = identities.iter().collect();
+        KeyAuthPlugin::new(serde_yaml::from_str(cfg).unwrap(), &identities).unwrap()
+    }
+
+    fn request(uri: &str, key: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        if let Some(key) = key {
+            req.insert_header("x-api-key", key).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn keys_are_resolved_from_the_header_then_the_query() {
+        let config = plugin("{query: apikey}").config;
+        assert_eq!(config.resolve(&request("/", Some("bob-key"))), Some("bob"));
+        assert_eq!(
+            config.resolve(&request("/?apikey=bob-key", Some("a+b/c=="))),
+            Some("alice")
+        );
+        assert_eq!(
+            config.resolve(&request("/?x=1&apikey=a%2Bb%2Fc%3D%3D", None)),
+            Some("alice")
+        );
+        assert_eq!(config.resolve(&request("/?apikey=a+b/c==", None)), None);
+        assert_eq!(config.resolve(&request("/?apikey=nope", None)), None);
+        assert_eq!(config.resolve(&request("/", None)), None);
+        let header_only = plugin("{}").config;
+        assert_eq!(
+            header_only.resolve(&request("/?apikey=bob-key", None)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_are_removed_once_resolved() {
+        let plugin = plugin("{query: apikey}");
+        let mut session = test_session(
+            "GET /a?apikey=bob-key&b=1 HTTP/1.1\r\nHost: h\r\nX-Api-Key: bob-key\r\n\r\n",
+        )
+        .await;
+        let mut ctx = PluginCtx::default();
+        assert!(!plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert_eq!(ctx.identity.as_deref(), Some("bob"));
+        let req = session.req_header();
+        assert!(!req.headers.contains_key("x-api-key"));
+        assert_eq!(req.uri, "/a?b=1");
+    }
+
+    #[test]
+    fn keys_are_unique_across_identities() {
+        let identities = identities(
+            r#"
+            - {name: a, key_auth: {keys: [k1, shared]}}
+            - {name: b, key_auth: {keys: [shared]}}
+            "#,
+        );
+        let identities: Vec<_> = identities.iter().collect();
+        assert!(matches!(
+            create_key_auth(None, &identities),
+            Err(AuthError::DuplicateKey { .. })
+        ));
+    }
+}
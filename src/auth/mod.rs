@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use http::{header, StatusCode};
+use once_cell::sync::Lazy;
+use pingora::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    clusters::ClusterManager,
+    config::def::{Auth, AuthType, Identity},
+    core::plugin::Plugin,
+    utils::send_response,
+};
+use errors::*;
+
+pub mod basic;
+pub mod client;
+pub mod errors;
+pub mod forward;
+pub mod hmac;
+pub mod jwt;
+pub mod key;
+pub mod mtls;
+pub mod oidc;
+
+static UNAUTHORIZED: Lazy<Bytes> = Lazy::new(|| Bytes::from("unauthorized"));
+
+pub type AuthResult<T> = Result<T, errors::AuthError>;
+
+/// Identities declared in the config, indexed by name
+pub type Identities = HashMap<String, Identity>;
+
+/// Indexes the identities by their names
+pub fn build_identities(identities: Vec<Identity>) -> Identities {
+    identities
+        .into_iter()
+        .map(|identity| (identity.name.clone(), identity))
+        .collect()
+}
+
+/// Creates the authenticator of a route
+///
+/// An authenticator is a plugin which always runs before the other plugins of the route. Once a
+/// request is authenticated, the name of the identity is stored in [`PluginCtx::identity`].
+/// Identities whose `allowed_routes` don't include `route` aren't accepted.
+/// `forward_auth` and `openid_connect` delegate the decision to an external service and don't
+/// resolve identities.
+///
+/// [`PluginCtx::identity`]: crate::core::plugin::PluginCtx::identity
+pub fn create_authenticator(
+    route: &str,
+    auth: Auth,
+    identities: &Identities,
+    clusters: &ClusterManager,
+) -> AuthResult<Box<dyn Plugin>> {
+    let allowed = match &auth.allowed_identities {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                identities
+                    .get(name)
+                    .ok_or(AuthError::UnknownIdentity { name: name.clone() })
+            })
+            .collect::<AuthResult<Vec<_>>>()?,
+        None => identities.values().collect(),
+    };
+    let allowed: Vec<_> = allowed
+        .into_iter()
+        .filter(|identity| {
+            identity
+                .allowed_routes
+                .as_ref()
+                .is_none_or(|routes| routes.iter().any(|r| r == route))
+        })
+        .collect();
+    match auth.auth_type {
+        AuthType::BasicAuth => basic::create_basic_auth(auth.config, &allowed),
+        AuthType::JwtAuth => jwt::create_jwt_auth(auth.config, &allowed),
+        AuthType::HmacAuth => hmac::create_hmac_auth(auth.config, &allowed),
+        AuthType::ForwardAuth => forward::create_forward_auth(auth.config, clusters),
+        AuthType::KeyAuth => key::create_key_auth(auth.config, &allowed),
+        AuthType::MtlsAuth => mtls::create_mtls_auth(&allowed),
+        AuthType::OpenidConnect => oidc::create_oidc_auth(auth.config, clusters),
+    }
+}
+
+/// Parses the auth type specific config, falling back to the default if not configured
+fn parse_config<T: DeserializeOwned + Default>(
+    cfg: Option<YamlValue>,
+    auth_type: AuthType,
+) -> AuthResult<T> {
+    match cfg {
+        Some(cfg) => serde_yaml::from_value(cfg).context(YamlErrSnafu { auth_type }),
+        None => Ok(T::default()),
+    }
+}
+
+/// Rejects the request with 401 and the `WWW-Authenticate` challenge
+async fn unauthorized(session: &mut Session, challenge: String) -> Result<()> {
+    let headers = HashMap::from([(header::WWW_AUTHENTICATE.to_string(), challenge)]);
+    send_response(
+        session,
+        StatusCode::UNAUTHORIZED,
+        None,
+        Some(UNAUTHORIZED.clone()),
+        Some(headers),
+    )
+    .await
+}
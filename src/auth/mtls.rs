@@ -0,0 +1,99 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::StatusCode;
+use once_cell::sync::Lazy;
+use pingora::prelude::*;
+
+use crate::{
+    auth::{errors::*, AuthResult},
+    config::def::{AuthType, Identity},
+    core::plugin::{ClientCert, Plugin, PluginCtx},
+    utils::send_response,
+};
+
+static FORBIDDEN: Lazy<Bytes> = Lazy::new(|| Bytes::from("forbidden"));
+
+pub fn create_mtls_auth(identities: &[&Identity]) -> AuthResult<Box<dyn Plugin>> {
+    let mut subjects = HashMap::new();
+    let mut sans = HashMap::new();
+    for identity in identities {
+        let Some(mtls) = &identity.mtls_auth else {
+            continue;
+        };
+        for subject in &mtls.subjects {
+            let other = subjects.insert(subject.clone(), identity.name.clone());
+            if let Some(other) = other.filter(|other| *other != identity.name) {
+                return Err(AuthError::DuplicateSubject {
+                    subject: subject.clone(),
+                    identity: identity.name.clone(),
+                    other,
+                });
+            }
+        }
+        for san in &mtls.sans {
+            let other = sans.insert(san.clone(), identity.name.clone());
+            if let Some(other) = other.filter(|other| *other != identity.name) {
+                return Err(AuthError::DuplicateSan {
+                    san: san.clone(),
+                    identity: identity.name.clone(),
+                    other,
+                });
+            }
+        }
+    }
+    if subjects.is_empty() && sans.is_empty() {
+        return Err(AuthError::NoCredential {
+            auth_type: AuthType::MtlsAuth,
+        });
+    }
+    Ok(Box::new(MtlsAuthPlugin {
+        config: Arc::new(MtlsConfig { subjects, sans }),
+    }))
+}
+
+struct MtlsConfig {
+    /// Names of the identities indexed by certificate subject
+    subjects: HashMap<String, String>,
+    /// Names of the identities indexed by subject alternative name
+    sans: HashMap<String, String>,
+}
+
+impl MtlsConfig {
+    /// The subject is matched first, then the SANs in certificate order
+    fn resolve(&self, cert: &ClientCert) -> Option<&str> {
+        self.subjects
+            .get(&cert.subject)
+            .or_else(|| cert.sans.iter().find_map(|san| self.sans.get(san)))
+            .map(|identity| identity.as_str())
+    }
+}
+
+/// Authenticates requests by the client certificate verified on a mutual TLS listener
+pub struct MtlsAuthPlugin {
+    config: Arc<MtlsConfig>,
+}
+
+#[async_trait]
+impl Plugin for MtlsAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let identity = ctx
+            .client_cert
+            .as_ref()
+            .and_then(|cert| self.config.resolve(cert));
+        if let Some(identity) = identity {
+            ctx.identity = Some(identity.to_string());
+            return Ok(false);
+        }
+        send_response(
+            session,
+            StatusCode::FORBIDDEN,
+            None,
+            Some(FORBIDDEN.clone()),
+            None,
+        )
+        .await?;
+        Ok(true)
+    }
+}
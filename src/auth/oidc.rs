@@ -0,0 +1,847 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, HeaderName, Method, StatusCode, Uri};
+use log::debug;
+use openssl::{
+    rand::rand_bytes,
+    sha::sha256,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use pingora::{http::ResponseHeader, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    auth::{
+        client::ClusterClient,
+        errors::*,
+        jwt::{claim_to_header, now, numeric_date, KeySet, Token, BASE64_URL},
+        AuthResult, UNAUTHORIZED,
+    },
+    clusters::ClusterManager,
+    config::def::AuthType,
+    core::plugin::{Plugin, PluginCtx},
+    utils::send_response,
+};
+
+/// How long a login may take between the redirect to the IdP and the callback
+const LOGIN_TTL: u64 = 600;
+
+/// Tolerance when checking `exp` of the id token
+const CLOCK_SKEW: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+struct OidcAuthConfig {
+    /// Cluster of the token endpoint
+    cluster: String,
+    /// If set, the `iss` claim of the id token must match
+    issuer: Option<String>,
+    /// Url of the IdP browsers are redirected to for login
+    authorization_endpoint: String,
+    /// Path of the token endpoint in the cluster
+    token_endpoint: String,
+    /// Host header of the token requests, the host of `authorization_endpoint` by default
+    token_host: Option<String>,
+    client_id: String,
+    /// Also verifies HS256 id tokens
+    client_secret: String,
+    /// PEM file of the key the IdP signs the id tokens with
+    public_key: Option<String>,
+    /// JWKS file of the keys the IdP signs the id tokens with
+    jwks: Option<String>,
+    /// Callback url registered at the IdP, requests to its path are handled by the plugin
+    redirect_uri: String,
+    #[serde(default = "default_scopes")]
+    scopes: Vec<String>,
+    /// Secret the cookies are encrypted with, at least 32 bytes
+    session_secret: String,
+    #[serde(default = "default_cookie_name")]
+    cookie_name: String,
+    #[serde(default = "default_session_ttl", with = "humantime_serde")]
+    session_ttl: Duration,
+    /// Whether the cookies are only sent over https
+    #[serde(default = "default_secure_cookie")]
+    secure_cookie: bool,
+    /// Claims of the id token forwarded to upstream, claim name -> header name
+    #[serde(default = "default_forward_claims")]
+    forward_claims: HashMap<String, String>,
+    /// Timeout of the token requests
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: Duration,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string()]
+}
+
+fn default_cookie_name() -> String {
+    "penguin_session".to_string()
+}
+
+fn default_session_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_secure_cookie() -> bool {
+    true
+}
+
+fn default_forward_claims() -> HashMap<String, String> {
+    HashMap::from([("sub".to_string(), "x-user-id".to_string())])
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+pub fn create_oidc_auth(
+    cfg: Option<YamlValue>,
+    clusters: &ClusterManager,
+) -> AuthResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(AuthError::LackConfig {
+        auth_type: AuthType::OpenidConnect,
+    })?;
+    let config: OidcAuthConfig = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        auth_type: AuthType::OpenidConnect,
+    })?;
+    Ok(Box::new(OidcAuthPlugin::new(config, clusters)?))
+}
+
+impl OidcAuthPlugin {
+    fn new(config: OidcAuthConfig, clusters: &ClusterManager) -> AuthResult<Self> {
+        let invalid = |msg: String| AuthError::InvalidConfig {
+            auth_type: AuthType::OpenidConnect,
+            msg,
+        };
+        if config.session_secret.len() < 32 {
+            return Err(invalid(
+                "session_secret must be at least 32 bytes".to_string(),
+            ));
+        }
+        let redirect_uri = Uri::from_str(&config.redirect_uri)
+            .map_err(|e| invalid(format!("invalid redirect_uri, error: {}", e)))?;
+        let authorization_endpoint = Uri::from_str(&config.authorization_endpoint)
+            .map_err(|e| invalid(format!("invalid authorization_endpoint, error: {}", e)))?;
+        let token_host = config
+            .token_host
+            .or_else(|| authorization_endpoint.authority().map(|a| a.to_string()))
+            .ok_or_else(|| invalid("token_host is required".to_string()))?;
+        let forward_claims = config
+            .forward_claims
+            .iter()
+            .map(|(claim, name)| {
+                let name = HeaderName::from_str(name).context(InvalidHeaderNameSnafu {
+                    name: name.to_string(),
+                })?;
+                Ok((claim.clone(), name))
+            })
+            .collect::<AuthResult<Vec<_>>>()?;
+        let keys = KeySet::load(
+            Some(&config.client_secret),
+            config.public_key.as_deref(),
+            config.jwks.as_deref(),
+            invalid,
+        )?;
+        Ok(OidcAuthPlugin {
+            config: Arc::new(OidcConfig {
+                client: ClusterClient::new(&config.cluster, clusters, config.timeout)?,
+                issuer: config.issuer,
+                authorization_endpoint: config.authorization_endpoint,
+                token_endpoint: config.token_endpoint,
+                token_host,
+                client_id: config.client_id,
+                client_secret: config.client_secret,
+                keys,
+                callback_path: redirect_uri.path().to_string(),
+                redirect_uri: config.redirect_uri,
+                scope: config.scopes.join(" "),
+                cipher: CookieCipher::new(&config.session_secret),
+                state_cookie: format!("{}_state", config.cookie_name),
+                cookie_name: config.cookie_name,
+                session_ttl: config.session_ttl.as_secs(),
+                secure_cookie: config.secure_cookie,
+                forward_claims,
+            }),
+        })
+    }
+}
+
+/// Login in progress, kept in the state cookie until the IdP redirects back
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    /// PKCE code verifier
+    verifier: String,
+    /// Uri the browser is sent back to once logged in
+    target: String,
+    exp: u64,
+}
+
+/// Authenticated user, kept in the session cookie
+#[derive(Serialize, Deserialize)]
+struct UserSession {
+    /// `sub` and the forwarded claims of the id token
+    claims: Map<String, Value>,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Encrypts cookies with AES-256-GCM, the cookie name is authenticated along with the value so
+/// that a cookie can't be replayed under another name
+struct CookieCipher {
+    key: [u8; 32],
+}
+
+impl CookieCipher {
+    fn new(secret: &str) -> Self {
+        Self {
+            key: sha256(secret.as_bytes()),
+        }
+    }
+
+    fn seal<T: Serialize>(&self, name: &str, value: &T) -> Result<String> {
+        let plain = serde_json::to_vec(value)
+            .or_err(ErrorType::InternalError, "failed to serialize cookie")?;
+        let mut iv = [0u8; 12];
+        rand_bytes(&mut iv).or_err(ErrorType::InternalError, "failed to generate iv")?;
+        let mut tag = [0u8; 16];
+        let sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&iv),
+            name.as_bytes(),
+            &plain,
+            &mut tag,
+        )
+        .or_err(ErrorType::InternalError, "failed to encrypt cookie")?;
+        Ok(BASE64_URL.encode([&iv[..], &tag[..], &sealed[..]].concat()))
+    }
+
+    fn open<T: DeserializeOwned>(&self, name: &str, cookie: &str) -> Option<T> {
+        let data = BASE64_URL.decode(cookie).ok()?;
+        if data.len() < 28 {
+            return None;
+        }
+        let (iv, rest) = data.split_at(12);
+        let (tag, sealed) = rest.split_at(16);
+        let plain = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(iv),
+            name.as_bytes(),
+            sealed,
+            tag,
+        )
+        .ok()?;
+        serde_json::from_slice(&plain).ok()
+    }
+}
+
+struct OidcConfig {
+    client: ClusterClient,
+    issuer: Option<String>,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    token_host: String,
+    client_id: String,
+    client_secret: String,
+    keys: KeySet,
+    redirect_uri: String,
+    callback_path: String,
+    scope: String,
+    cipher: CookieCipher,
+    cookie_name: String,
+    state_cookie: String,
+    session_ttl: u64,
+    secure_cookie: bool,
+    forward_claims: Vec<(String, HeaderName)>,
+}
+
+/// Returns whether the redirect target stays on this site, `//host` and `/\host` are taken by
+/// browsers as urls of other hosts
+fn is_local(target: &str) -> bool {
+    target.starts_with('/') && !target.starts_with("//") && !target.starts_with("/\\")
+}
+
+fn random_token() -> Result<String> {
+    let mut buf = [0u8; 32];
+    rand_bytes(&mut buf).or_err(ErrorType::InternalError, "failed to generate token")?;
+    Ok(BASE64_URL.encode(buf))
+}
+
+impl OidcConfig {
+    fn cookie(&self, name: &str, value: &str, path: &str, max_age: u64) -> String {
+        let secure = if self.secure_cookie { "; Secure" } else { "" };
+        format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            name, value, path, max_age, secure
+        )
+    }
+
+    /// Redirects browsers to the IdP, other requests can't follow the login and get 401
+    async fn login(&self, session: &mut Session) -> Result<()> {
+        let req = session.req_header();
+        if req.method != Method::GET && req.method != Method::HEAD {
+            return send_response(
+                session,
+                StatusCode::UNAUTHORIZED,
+                None,
+                Some(UNAUTHORIZED.clone()),
+                None,
+            )
+            .await;
+        }
+        let target = req
+            .uri
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .filter(|target| is_local(target))
+            .unwrap_or("/");
+        let (location, cookie) = self.authorization_request(target)?;
+        redirect(session, &location, &[cookie]).await
+    }
+
+    /// Returns the url of the IdP to log in at and the state cookie of the login
+    fn authorization_request(&self, target: &str) -> Result<(String, String)> {
+        let pending = PendingLogin {
+            state: random_token()?,
+            nonce: random_token()?,
+            verifier: random_token()?,
+            target: target.to_string(),
+            exp: now() + LOGIN_TTL,
+        };
+        let challenge = BASE64_URL.encode(sha256(pending.verifier.as_bytes()));
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", &self.scope)
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        let separator = if self.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let location = format!("{}{}{}", self.authorization_endpoint, separator, query);
+        let state = self.cipher.seal(&self.state_cookie, &pending)?;
+        let cookie = self.cookie(&self.state_cookie, &state, &self.callback_path, LOGIN_TTL);
+        Ok((location, cookie))
+    }
+
+    /// Handles the redirect back from the IdP, the code is exchanged for an id token which is
+    /// turned into a session
+    async fn callback(&self, session: &mut Session, ctx: &PluginCtx) -> Result<()> {
+        let req = session.req_header();
+        let params: HashMap<String, String> = req
+            .uri
+            .query()
+            .map(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let Some(pending) = self.pending_login(req, ctx, &params) else {
+            let body = Bytes::from("invalid login state");
+            return send_response(session, StatusCode::BAD_REQUEST, None, Some(body), None).await;
+        };
+        if let Some(error) = params.get("error") {
+            debug!("openid connect login failed: {}", error);
+        }
+        let Some(code) = params.get("code") else {
+            return send_response(
+                session,
+                StatusCode::UNAUTHORIZED,
+                None,
+                Some(UNAUTHORIZED.clone()),
+                None,
+            )
+            .await;
+        };
+        let id_token = self.exchange(code, &pending.verifier).await.map_err(|e| {
+            Error::because(
+                ErrorType::HTTPStatus(503),
+                format!(
+                    "openid connect token request to cluster {} failed",
+                    self.client.cluster()
+                ),
+                e,
+            )
+        })?;
+        let claims = match id_token.and_then(|token| self.verify(&token, &pending.nonce)) {
+            Ok(claims) => claims,
+            Err(reason) => {
+                debug!("openid connect login rejected: {}", reason);
+                return send_response(
+                    session,
+                    StatusCode::UNAUTHORIZED,
+                    None,
+                    Some(UNAUTHORIZED.clone()),
+                    None,
+                )
+                .await;
+            }
+        };
+        let user = UserSession {
+            claims,
+            exp: now() + self.session_ttl,
+        };
+        let value = self.cipher.seal(&self.cookie_name, &user)?;
+        let cookies = [
+            self.cookie(&self.cookie_name, &value, "/", self.session_ttl),
+            self.cookie(&self.state_cookie, "", &self.callback_path, 0),
+        ];
+        let target = Some(pending.target.as_str())
+            .filter(|target| is_local(target))
+            .unwrap_or("/");
+        redirect(session, target, &cookies).await
+    }
+
+    /// Returns the login of the state cookie if it has not expired and matches the `state`
+    /// parameter of the callback
+    fn pending_login(
+        &self,
+        req: &RequestHeader,
+        ctx: &PluginCtx,
+        params: &HashMap<String, String>,
+    ) -> Option<PendingLogin> {
+        ctx.cookie(req, &self.state_cookie)
+            .and_then(|cookie| self.cipher.open::<PendingLogin>(&self.state_cookie, cookie))
+            .filter(|pending| pending.exp > now())
+            .filter(|pending| params.get("state") == Some(&pending.state))
+    }
+
+    /// Returns the user of the session cookie if the session has not expired
+    fn user(&self, req: &RequestHeader, ctx: &PluginCtx) -> Option<UserSession> {
+        ctx.cookie(req, &self.cookie_name)
+            .and_then(|cookie| self.cipher.open::<UserSession>(&self.cookie_name, cookie))
+            .filter(|user| user.exp > now())
+    }
+
+    /// Exchanges the code at the token endpoint, a rejection of the IdP is returned as the inner
+    /// error
+    async fn exchange(
+        &self,
+        code: &str,
+        verifier: &str,
+    ) -> Result<std::result::Result<String, &'static str>> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("client_secret", &self.client_secret)
+            .append_pair("code_verifier", verifier)
+            .finish();
+        let mut req = RequestHeader::build("POST", self.token_endpoint.as_bytes(), None)?;
+        req.insert_header(header::HOST, &self.token_host)?;
+        req.insert_header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")?;
+        req.insert_header(header::ACCEPT, "application/json")?;
+        req.insert_header(header::CONTENT_LENGTH, body.len())?;
+        let (resp, body) = self.client.send(req, Some(Bytes::from(body))).await?;
+        if !resp.status.is_success() {
+            debug!(
+                "token endpoint responded {}: {}",
+                resp.status,
+                String::from_utf8_lossy(&body)
+            );
+            return Ok(Err("code rejected by the token endpoint"));
+        }
+        Ok(serde_json::from_slice::<TokenResponse>(&body)
+            .map(|resp| resp.id_token)
+            .map_err(|_| "malformed token response"))
+    }
+
+    /// Checks the signature and the claims of the id token
+    fn verify(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> std::result::Result<Map<String, Value>, &'static str> {
+        let token = Token::decode(id_token)?;
+        if !self.keys.verify(&token) {
+            return Err("invalid signature");
+        }
+        let claims = token.claims;
+        let claim = |name: &str| claims.get(name).and_then(|v| v.as_str());
+        if let Some(issuer) = &self.issuer {
+            if claim("iss") != Some(issuer.as_str()) {
+                return Err("issuer mismatch");
+            }
+        }
+        let audience_matched = match claims.get("aud") {
+            Some(Value::String(aud)) => *aud == self.client_id,
+            Some(Value::Array(auds)) => {
+                auds.iter().any(|aud| aud.as_str() == Some(&self.client_id))
+            }
+            _ => false,
+        };
+        if !audience_matched {
+            return Err("audience mismatch");
+        }
+        let exp = claims
+            .get("exp")
+            .and_then(numeric_date)
+            .ok_or("malformed exp")?;
+        if now() > exp.saturating_add(CLOCK_SKEW) {
+            return Err("id token expired");
+        }
+        if claim("nonce") != Some(nonce) {
+            return Err("nonce mismatch");
+        }
+        if claim("sub").is_none() {
+            return Err("missing sub");
+        }
+        let kept = claims
+            .into_iter()
+            .filter(|(name, _)| {
+                name == "sub" || self.forward_claims.iter().any(|(claim, _)| claim == name)
+            })
+            .collect();
+        Ok(kept)
+    }
+
+    /// Returns the `Cookie` header without the cookies of the plugin
+    fn strip_cookies(&self, req: &RequestHeader) -> Option<String> {
+        let pairs: Vec<_> = req
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .map(|pair| pair.trim())
+            .filter(|pair| {
+                let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+                !pair.is_empty() && name != self.cookie_name && name != self.state_cookie
+            })
+            .collect();
+        (!pairs.is_empty()).then(|| pairs.join("; "))
+    }
+}
+
+async fn redirect(session: &mut Session, location: &str, cookies: &[String]) -> Result<()> {
+    let mut resp = ResponseHeader::build(StatusCode::FOUND, Some(4))?;
+    resp.insert_header(header::LOCATION, location)?;
+    for cookie in cookies {
+        resp.append_header(header::SET_COOKIE, cookie)?;
+    }
+    resp.insert_header(header::CACHE_CONTROL, "no-store")?;
+    resp.insert_header(header::CONTENT_LENGTH, 0)?;
+    session.write_response_header(Box::new(resp), true).await
+}
+
+/// Logs browsers in with the OpenID Connect authorization code flow
+///
+/// Sessions are kept in an encrypted cookie, the claims of logged in users are forwarded to
+/// upstream in headers.
+pub struct OidcAuthPlugin {
+    config: Arc<OidcConfig>,
+}
+
+#[async_trait]
+impl Plugin for OidcAuthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let config = &self.config;
+        if session.req_header().uri.path() == config.callback_path {
+            config.callback(session, ctx).await?;
+            return Ok(true);
+        }
+        let Some(user) = config.user(session.req_header(), ctx) else {
+            config.login(session).await?;
+            return Ok(true);
+        };
+        // the session cookie never reaches the upstream, and neither do headers sent by the
+        // client under the names of the forwarded claims
+        let cookie = config.strip_cookies(session.req_header());
+        let req = session.req_header_mut();
+        match cookie {
+            Some(cookie) => req.insert_header(header::COOKIE, cookie)?,
+            None => {
+                req.remove_header(&header::COOKIE);
+            }
+        }
+        for (claim, name) in &config.forward_claims {
+            req.remove_header(name);
+            if let Some(value) = user.claims.get(claim).and_then(claim_to_header) {
+                req.insert_header(name.clone(), value)?;
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+cluster: idp
+issuer: https://idp.example.com
+authorization_endpoint: https://idp.example.com/authorize
+token_endpoint: /token
+client_id: app
+client_secret: secret
+redirect_uri: https://app.example.com/callback
+session_secret: 0123456789abcdef0123456789abcdef
+"#;
+
+    fn plugin(addr: &str) -> OidcAuthPlugin {
+        let cfg = serde_yaml::from_str(&format!(
+            "[{{name: idp, resolver: static, lb_policy: random, config: {{endpoints: [\"{}\"]}}}}]",
+            addr
+        ))
+        .unwrap();
+        let clusters = ClusterManager::new(cfg, &HashMap::new()).unwrap();
+        OidcAuthPlugin::new(serde_yaml::from_str(CONFIG).unwrap(), &clusters).unwrap()
+    }
+
+    /// Builds an id token signed with the client secret
+    fn id_token(claims: Value) -> String {
+        let message = format!(
+            "{}.{}",
+            BASE64_URL.encode(json!({"alg": "HS256"}).to_string()),
+            BASE64_URL.encode(claims.to_string())
+        );
+        let key = PKey::hmac(b"secret").unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(message.as_bytes()).unwrap();
+        format!("{}.{}", message, BASE64_URL.encode(signature))
+    }
+
+    fn claims(nonce: &str) -> Value {
+        json!({
+            "iss": "https://idp.example.com",
+            "aud": "app",
+            "sub": "u1",
+            "email": "u1@example.com",
+            "nonce": nonce,
+            "exp": now() + 60,
+        })
+    }
+
+    /// Builds the callback request with `cookie`, which is a `Set-Cookie` value
+    fn callback(query: &str, cookie: Option<&str>) -> (RequestHeader, HashMap<String, String>) {
+        let mut req =
+            RequestHeader::build("GET", format!("/callback?{}", query).as_bytes(), None).unwrap();
+        if let Some(cookie) = cookie {
+            let pair = cookie.split_once(';').map_or(cookie, |(pair, _)| pair);
+            req.insert_header(header::COOKIE, pair).unwrap();
+        }
+        let params = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        (req, params)
+    }
+
+    /// Answers one token request with `id_token`, returns the body of the request
+    async fn token_endpoint(listener: TcpListener, id_token: String) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        let body = loop {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "token request is incomplete");
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf);
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                continue;
+            };
+            let len: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if body.len() >= len {
+                break body.to_string();
+            }
+        };
+        let json = json!({ "id_token": id_token }).to_string();
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            json.len(),
+            json
+        );
+        stream.write_all(resp.as_bytes()).await.unwrap();
+        body
+    }
+
+    #[tokio::test]
+    async fn state_nonce_and_verifier_round_trip_through_the_login() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plugin = plugin(&listener.local_addr().unwrap().to_string());
+        let config = &plugin.config;
+
+        let (location, cookie) = config.authorization_request("/dash?x=1").unwrap();
+        let (endpoint, query) = location.split_once('?').unwrap();
+        assert_eq!(endpoint, "https://idp.example.com/authorize");
+        let login: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        assert_eq!(login["client_id"], "app");
+        assert_eq!(login["redirect_uri"], "https://app.example.com/callback");
+        assert_eq!(login["code_challenge_method"], "S256");
+        assert!(cookie.starts_with("penguin_session_state="));
+        assert!(cookie.contains("; Path=/callback;"));
+
+        let (req, params) = callback(&format!("code=c1&state={}", login["state"]), Some(&cookie));
+        let pending = config
+            .pending_login(&req, &PluginCtx::default(), &params)
+            .unwrap();
+        assert_eq!(pending.target, "/dash?x=1");
+        assert_eq!(
+            BASE64_URL.encode(sha256(pending.verifier.as_bytes())),
+            login["code_challenge"]
+        );
+
+        let token = id_token(claims(&login["nonce"]));
+        let endpoint = tokio::spawn(token_endpoint(listener, token.clone()));
+        let exchanged = config.exchange("c1", &pending.verifier).await.unwrap();
+        assert_eq!(exchanged, Ok(token.clone()));
+        let sent: HashMap<String, String> =
+            form_urlencoded::parse(endpoint.await.unwrap().as_bytes())
+                .into_owned()
+                .collect();
+        assert_eq!(sent["code"], "c1");
+        assert_eq!(sent["code_verifier"], pending.verifier);
+
+        let claims = config.verify(&token, &pending.nonce).unwrap();
+        assert_eq!(claims.get("sub"), Some(&json!("u1")));
+        assert_eq!(claims.get("email"), None);
+    }
+
+    #[test]
+    fn callbacks_must_carry_the_state_of_the_cookie() {
+        let plugin = plugin("127.0.0.1:1");
+        let config = &plugin.config;
+        let (_, cookie) = config.authorization_request("/").unwrap();
+        let pending = |query: &str, cookie: Option<&str>| {
+            let (req, params) = callback(query, cookie);
+            config.pending_login(&req, &PluginCtx::default(), &params)
+        };
+        assert!(pending("code=c1&state=forged", Some(&cookie)).is_none());
+        assert!(pending("code=c1", Some(&cookie)).is_none());
+
+        let login = PendingLogin {
+            state: "s1".to_string(),
+            nonce: "n1".to_string(),
+            verifier: "v1".to_string(),
+            target: "/".to_string(),
+            exp: now() + 60,
+        };
+        let sealed = config.cipher.seal(&config.state_cookie, &login).unwrap();
+        let cookie = format!("penguin_session_state={}", sealed);
+        assert!(pending("code=c1&state=s1", Some(&cookie)).is_some());
+        assert!(pending("code=c1&state=s1", None).is_none());
+
+        // a cookie sealed under another name is not taken as the state
+        let sealed = config.cipher.seal(&config.cookie_name, &login).unwrap();
+        let cookie = format!("penguin_session_state={}", sealed);
+        assert!(pending("code=c1&state=s1", Some(&cookie)).is_none());
+
+        let expired = PendingLogin {
+            exp: now() - 1,
+            ..login
+        };
+        let sealed = config.cipher.seal(&config.state_cookie, &expired).unwrap();
+        let cookie = format!("penguin_session_state={}", sealed);
+        assert!(pending("code=c1&state=s1", Some(&cookie)).is_none());
+    }
+
+    #[test]
+    fn id_tokens_must_carry_the_nonce_of_the_login() {
+        let plugin = plugin("127.0.0.1:1");
+        let config = &plugin.config;
+        assert!(config.verify(&id_token(claims("n1")), "n1").is_ok());
+        assert_eq!(
+            config.verify(&id_token(claims("n1")), "n2"),
+            Err("nonce mismatch")
+        );
+        let mut missing = claims("n1");
+        missing.as_object_mut().unwrap().remove("nonce");
+        assert_eq!(
+            config.verify(&id_token(missing), "n1"),
+            Err("nonce mismatch")
+        );
+
+        let mut other_client = claims("n1");
+        other_client["aud"] = json!("other");
+        assert_eq!(
+            config.verify(&id_token(other_client), "n1"),
+            Err("audience mismatch")
+        );
+        let mut expired = claims("n1");
+        expired["exp"] = json!(now() - CLOCK_SKEW - 1);
+        assert_eq!(
+            config.verify(&id_token(expired), "n1"),
+            Err("id token expired")
+        );
+        let mut fractional = claims("n1");
+        fractional["exp"] = json!(now() as f64 + 0.5);
+        assert!(config.verify(&id_token(fractional), "n1").is_ok());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let plugin = plugin("127.0.0.1:1");
+        let config = &plugin.config;
+        let user = |exp: u64, name: &str| {
+            let session = UserSession {
+                claims: Map::from_iter([("sub".to_string(), json!("u1"))]),
+                exp,
+            };
+            let sealed = config.cipher.seal(name, &session).unwrap();
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            req.insert_header(header::COOKIE, format!("penguin_session={}", sealed))
+                .unwrap();
+            config.user(&req, &PluginCtx::default())
+        };
+        let session = user(now() + 60, &config.cookie_name).unwrap();
+        assert_eq!(session.claims.get("sub"), Some(&json!("u1")));
+        assert!(user(now() - 1, &config.cookie_name).is_none());
+        assert!(user(now() + 60, &config.state_cookie).is_none());
+    }
+
+    #[test]
+    fn only_local_targets_are_redirected_to() {
+        assert!(is_local("/"));
+        assert!(is_local("/dash/x?a=1"));
+        assert!(!is_local("//evil.com/x"));
+        assert!(!is_local("/\\evil.com"));
+        assert!(!is_local("https://evil.com"));
+        assert!(!is_local(""));
+    }
+
+    #[test]
+    fn cookies_round_trip_under_their_name_only() {
+        let cipher = CookieCipher::new("0123456789abcdef0123456789abcdef");
+        let sealed = cipher.seal("a", &"value").unwrap();
+        assert_eq!(
+            cipher.open::<String>("a", &sealed).as_deref(),
+            Some("value")
+        );
+        assert_eq!(cipher.open::<String>("b", &sealed), None);
+        assert_eq!(cipher.open::<String>("a", "AAAA"), None);
+    }
+}
@@ -1,7 +1,8 @@
+use http::header::InvalidHeaderName;
 use matchit::InsertError;
 use snafu::Snafu;
 
-use crate::plugins::errors::PluginError;
+use crate::{auth::errors::AuthError, plugins::errors::PluginError};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
@@ -10,10 +11,25 @@ pub enum BuilderError {
     LackConfig { name: String },
     #[snafu(display("Failed to build plugin: {}, error: {:?}", name, source))]
     PluginBuild { source: PluginError, name: String },
+    #[snafu(display("Failed to build auth of route: {}, error: {}", route, source))]
+    Auth { source: AuthError, route: String },
+    #[snafu(display("Failed to build plugins of identity: {}, error: {}", identity, source))]
+    IdentityPlugins {
+        #[snafu(source(from(BuilderError, Box::new)))]
+        source: Box<BuilderError>,
+        identity: String,
+    },
     #[snafu(display("Lack uri for route: {}", name))]
     LackUri { name: String },
     #[snafu(display("Failed to compile regex: {}, error: {:?}", re, source))]
     Regexp { source: regex::Error, re: String },
+    #[snafu(display("Unsupported uri match for route: {}", name))]
+    UnsupportedUriMatch { name: String },
+    #[snafu(display("Invalid header name: {}, error: {:?}", name, source))]
+    InvalidHeaderName {
+        source: InvalidHeaderName,
+        name: String,
+    },
     #[snafu(display("Failed to insert route: {}, error: {:?}", path, source))]
     InsertRoute { source: InsertError, path: String },
 }
@@ -1,14 +1,17 @@
+use http::HeaderName;
 use regex::Regex;
 use snafu::ResultExt;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::{
-    clusters::{discovery::ResolverWrapper, Resolver},
-    config::def::{DiscoveryProvider, Plugin, ResolverType, Route, StrMatch},
+    auth::{create_authenticator, Identities},
+    clusters::{discovery::ResolverWrapper, ClusterManager, Resolver},
+    config::def::{Auth, DiscoveryProvider, Plugin, ResolverType, Route, StrMatch, Timeouts},
     core::plugin::Plugin as PluginTrait,
-    plugins::create_plugin_builder,
-    proxy::process::{MatchEntry, Pipeline},
+    plugins::{cors::CORS_PLUGIN_NAME, create_plugin_builder},
+    proxy::process::{ConsumerPlugins, MatchEntry, Pipeline, RouteConditions, ValueMatcher},
 };
 use errors::*;
 
@@ -29,29 +32,47 @@ pub fn init_discovery_providers(
     Ok(providers)
 }
 
-pub fn init_routes(cfg: Vec<Route>) -> BuilderResult<MatchEntry> {
+pub fn init_routes(
+    cfg: Vec<Route>,
+    identities: &Identities,
+    clusters: &ClusterManager,
+) -> BuilderResult<MatchEntry> {
     let mut matcher = MatchEntry::new();
     for one_route in cfg {
         // build plugins
-        let ppl = build_pipleline(one_route.plugins, &one_route.cluster)?;
+        let ppl = build_pipleline(
+            one_route.name.clone(),
+            one_route.auth,
+            identities,
+            clusters,
+            one_route.plugins,
+            &one_route.cluster,
+            one_route.timeouts,
+        )?;
 
         // build matcher
+        let conditions = build_conditions(one_route.matcher.headers, one_route.matcher.cookies)?;
         if let Some(uri) = one_route.matcher.uri {
             match uri {
                 StrMatch::Regexp(re) => {
                     let re = Regex::new(&re).context(RegexpSnafu { re })?;
-                    matcher.add_regex_route(re, ppl);
+                    matcher.add_regex_route(re, conditions, ppl);
                 }
                 StrMatch::Prefix(prefix) => {
                     matcher
-                        .insert_route(revise_prefix(&prefix).as_str(), ppl)
+                        .insert_route(revise_prefix(&prefix).as_str(), conditions, ppl)
                         .context(InsertRouteSnafu { path: prefix })?;
                 }
                 StrMatch::Exact(exact) => {
                     matcher
-                        .insert_route(&exact, ppl)
+                        .insert_route(&exact, conditions, ppl)
                         .context(InsertRouteSnafu { path: exact })?;
                 }
+                StrMatch::Present(_) => {
+                    return Err(BuilderError::UnsupportedUriMatch {
+                        name: one_route.name,
+                    });
+                }
             }
         } else {
             unimplemented!("uri is required for now");
@@ -60,6 +81,31 @@ pub fn init_routes(cfg: Vec<Route>) -> BuilderResult<MatchEntry> {
     Ok(matcher)
 }
 
+fn build_conditions(
+    headers: Option<HashMap<String, StrMatch>>,
+    cookies: Option<HashMap<String, StrMatch>>,
+) -> BuilderResult<RouteConditions> {
+    let mut header_matchers = vec![];
+    for (name, m) in headers.unwrap_or_default() {
+        let header = HeaderName::from_str(&name).context(InvalidHeaderNameSnafu { name })?;
+        header_matchers.push((header, build_value_matcher(m)?));
+    }
+    let mut cookie_matchers = vec![];
+    for (name, m) in cookies.unwrap_or_default() {
+        cookie_matchers.push((name, build_value_matcher(m)?));
+    }
+    Ok(RouteConditions::new(header_matchers, cookie_matchers))
+}
+
+fn build_value_matcher(m: StrMatch) -> BuilderResult<ValueMatcher> {
+    Ok(match m {
+        StrMatch::Regexp(re) => ValueMatcher::Regexp(Regex::new(&re).context(RegexpSnafu { re })?),
+        StrMatch::Prefix(prefix) => ValueMatcher::Prefix(prefix),
+        StrMatch::Exact(exact) => ValueMatcher::Exact(exact),
+        StrMatch::Present(present) => ValueMatcher::Present(present),
+    })
+}
+
 /// revise prefix to be a valid path for matchit
 /// if it suffix of *, remove * and append {*rest}
 /// else append {*rest}
@@ -71,14 +117,63 @@ fn revise_prefix(prefix: &str) -> String {
     }
 }
 
-fn build_pipleline(cfg: Option<Vec<Plugin>>, cluster: &str) -> BuilderResult<Arc<Pipeline>> {
-    let plugin_builder = build_plugin_list(cfg)?;
+fn build_pipleline(
+    name: String,
+    auth: Option<Auth>,
+    identities: &Identities,
+    clusters: &ClusterManager,
+    cfg: Option<Vec<Plugin>>,
+    cluster: &str,
+    timeouts: Timeouts,
+) -> BuilderResult<Arc<Pipeline>> {
+    let mut plugin_names = vec![];
+    let mut plugin_builder = vec![];
+    let mut cfg = cfg.unwrap_or_default();
+    // authenticator goes first so that plugins can rely on the identity, only cors goes ahead
+    // of it as preflights don't carry credentials, the other plugins keep their order
+    if let Some(auth) = auth {
+        let (cors, rest): (Vec<_>, Vec<_>) =
+            cfg.into_iter().partition(|pl| pl.name == CORS_PLUGIN_NAME);
+        plugin_names.extend(cors.iter().map(|pl| pl.name.clone()));
+        plugin_builder.extend(build_plugin_list(Some(cors))?);
+        cfg = rest;
+        plugin_names.push(format!("auth:{}", auth.auth_type.as_str()));
+        plugin_builder.push(
+            create_authenticator(&name, auth, identities, clusters)
+                .context(AuthSnafu { route: &name })?,
+        );
+    }
+    plugin_names.extend(cfg.iter().map(|pl| pl.name.clone()));
+    plugin_builder.extend(build_plugin_list(Some(cfg))?);
     Ok(Arc::new(Pipeline::new(
+        name,
         Arc::new(plugin_builder),
+        plugin_names,
         cluster.to_string(),
+        timeouts,
     )))
 }
 
+/// Builds the plugins of each identity, they are shared by all services
+pub fn build_consumer_plugins(identities: &Identities) -> BuilderResult<ConsumerPlugins> {
+    let mut consumers = HashMap::new();
+    for identity in identities.values() {
+        if identity
+            .plugins
+            .as_ref()
+            .is_none_or(|plugins| plugins.is_empty())
+        {
+            continue;
+        }
+        let plugins =
+            build_plugin_list(identity.plugins.clone()).context(IdentityPluginsSnafu {
+                identity: &identity.name,
+            })?;
+        consumers.insert(identity.name.clone(), Arc::new(plugins));
+    }
+    Ok(consumers)
+}
+
 pub fn build_plugin_list(cfg: Option<Vec<Plugin>>) -> BuilderResult<Vec<Box<dyn PluginTrait>>> {
     let mut plugin_builder = vec![];
     if let Some(plugins) = cfg {
@@ -90,3 +185,45 @@ pub fn build_plugin_list(cfg: Option<Vec<Plugin>>) -> BuilderResult<Vec<Box<dyn
     }
     Ok(plugin_builder)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::build_identities;
+
+    fn plugin_names(auth: Option<&str>) -> Vec<String> {
+        let identities =
+            build_identities(serde_yaml::from_str("[{name: a, key_auth: {keys: [k1]}}]").unwrap());
+        let clusters = ClusterManager::new(vec![], &HashMap::new()).unwrap();
+        let plugins = serde_yaml::from_str(
+            r#"
+            - name: request_id
+            - name: cors
+              config: {allow_origins: ["*"]}
+            - name: echo
+              config: {body: ok}
+            "#,
+        )
+        .unwrap();
+        let ppl = build_pipleline(
+            "r".to_string(),
+            auth.map(|auth| serde_yaml::from_str(auth).unwrap()),
+            &identities,
+            &clusters,
+            Some(plugins),
+            "c",
+            Timeouts::default(),
+        )
+        .unwrap();
+        ppl.plugin_names().to_vec()
+    }
+
+    #[test]
+    fn plugins_keep_their_order_with_cors_ahead_of_auth() {
+        assert_eq!(plugin_names(None), ["request_id", "cors", "echo"]);
+        assert_eq!(
+            plugin_names(Some("{type: key_auth}")),
+            ["cors", "auth:key_auth", "request_id", "echo"]
+        );
+    }
+}
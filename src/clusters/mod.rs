@@ -5,7 +5,7 @@ use crate::{
         discovery::{DnsDiscovery, StaticDiscovery},
         errors::*,
     },
-    config::def::{Cluster as ClusterConfig, ResolverType},
+    config::def::{Cluster as ClusterConfig, ResolverType, Timeouts},
     core::lb::LB,
 };
 use async_trait::async_trait;
@@ -25,6 +25,7 @@ pub trait Resolver: Send + Sync {
 
 pub struct ClusterManager {
     clusters: HashMap<String, Arc<dyn LB>>,
+    timeouts: HashMap<String, Timeouts>,
 }
 
 impl ClusterManager {
@@ -33,7 +34,9 @@ impl ClusterManager {
         resolvers: &HashMap<ResolverType, Arc<dyn Resolver>>,
     ) -> ClusterResult<Self> {
         let mut clusters: HashMap<String, Arc<dyn LB>> = HashMap::new();
+        let mut timeouts = HashMap::new();
         for cfg in cfgs {
+            timeouts.insert(cfg.name.clone(), cfg.timeouts);
             match cfg.resolver {
                 ResolverType::DNS => {
                     let resolver = resolvers.get(&ResolverType::DNS).cloned().ok_or(
@@ -58,11 +61,15 @@ impl ClusterManager {
                 }
             }
         }
-        Ok(Self { clusters })
+        Ok(Self { clusters, timeouts })
     }
     pub fn get_cluster(&self, name: &str) -> Option<Arc<dyn LB>> {
         self.clusters.get(name).cloned()
     }
+    /// Returns the default timeouts of the cluster
+    pub fn get_timeouts(&self, name: &str) -> Timeouts {
+        self.timeouts.get(name).copied().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -17,4 +17,22 @@ pub enum Command {
     ValidateConfig,
     #[command(name = "run", about = "Run the server")]
     Run,
+    #[command(name = "route", about = "Show which route a request would hit")]
+    Route(RouteArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct RouteArgs {
+    /// Only match against the service with this name
+    #[arg(short, long)]
+    pub service: Option<String>,
+    #[arg(short, long, default_value = "GET")]
+    pub method: String,
+    #[arg(long)]
+    pub host: Option<String>,
+    #[arg(short, long)]
+    pub path: String,
+    /// Request header in the form of `key:value`, can be repeated
+    #[arg(short = 'H', long = "header", value_name = "KEY:VALUE")]
+    pub headers: Vec<String>,
 }
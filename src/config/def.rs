@@ -22,11 +22,18 @@ pub struct Identity {
     pub basic_auth: Option<BasicAuth>,
     pub hmac_auth: Option<HmacAuth>,
     pub jwt_auth: Option<JwtAuth>,
+    pub key_auth: Option<KeyAuth>,
+    pub mtls_auth: Option<MtlsAuth>,
+    /// Plugins applied to requests authenticated as this identity, after the route's plugins
+    pub plugins: Option<Vec<Plugin>>,
+    /// Names of the routes the identity can access, all routes if not set
+    pub allowed_routes: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BasicAuth {
     pub username: String,
+    /// Plain text password, or a bcrypt (`$2b$...`) or argon2 (`$argon2id$...`) hash of it
     pub password: String,
 }
 
@@ -38,8 +45,30 @@ pub struct HmacAuth {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JwtAuth {
+    /// Tokens whose `iss` claim equals the issuer are mapped to this identity
     pub issuer: String,
-    pub secret: String,
+    /// Secret of HS256 tokens
+    pub secret: Option<String>,
+    /// PEM file of the RSA or EC public key of RS256/ES256 tokens
+    pub public_key: Option<String>,
+    /// Local JWKS file, keys are selected by the `kid` of the token
+    pub jwks: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyAuth {
+    /// API keys of the identity, any of them is accepted
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MtlsAuth {
+    /// Accepted certificate subjects, formatted as `C=US,O=Acme,CN=client`
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    /// Accepted subject alternative names, i.e. DNS names, URIs, emails or ip addresses
+    #[serde(default)]
+    pub sans: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
@@ -75,10 +104,64 @@ pub enum Protocol {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SslConfig {
+    /// Default certificate, served when the SNI matches none of `certificates`
     #[serde(rename = "cert")]
     pub cert_path: String,
     #[serde(rename = "key")]
     pub key_path: String,
+    /// Certificates selected by the SNI of the handshake
+    #[serde(default)]
+    pub certificates: Vec<Certificate>,
+    /// Interval to check the certificate files for changes, they are also reloaded on SIGHUP
+    #[serde(default = "default_reload_interval", with = "humantime_serde")]
+    pub reload_interval: Duration,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// OpenSSL cipher list of TLS 1.2 and below
+    pub ciphers: Option<String>,
+    /// Cipher suites of TLS 1.3
+    pub ciphersuites: Option<String>,
+    /// CA bundle to verify client certificates with, enables mutual TLS
+    pub client_ca: Option<String>,
+    /// Whether clients must present a certificate when `client_ca` is set
+    #[serde(default)]
+    pub client_verify: ClientVerify,
+}
+
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Certificate {
+    #[serde(rename = "cert")]
+    pub cert_path: String,
+    #[serde(rename = "key")]
+    pub key_path: String,
+    /// Server names the certificate is served for, `*.example.com` matches one level of subdomain
+    pub server_names: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "tls1")]
+    Tls1,
+    #[serde(rename = "tls1.1")]
+    Tls1_1,
+    #[serde(rename = "tls1.2")]
+    Tls1_2,
+    #[serde(rename = "tls1.3")]
+    Tls1_3,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientVerify {
+    /// Handshakes without a client certificate fail
+    #[default]
+    Required,
+    /// Client certificates are verified if presented
+    Optional,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,12 +172,37 @@ pub struct Route {
     pub auth: Option<Auth>,
     pub plugins: Option<Vec<Plugin>>,
     pub cluster: String,
+    /// Overrides the timeouts of the cluster
+    #[serde(flatten)]
+    pub timeouts: Timeouts,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy)]
+pub struct Timeouts {
+    /// Deadline of the whole request, starting once the request header is read, so the time
+    /// spent reading the header is bounded by the listener's read timeout instead
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    /// Max time to wait on a single read or write, to either downstream or upstream
+    #[serde(default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fills the unset timeouts with the ones from `defaults`
+    pub fn or(self, defaults: Timeouts) -> Timeouts {
+        Timeouts {
+            timeout: self.timeout.or(defaults.timeout),
+            idle_timeout: self.idle_timeout.or(defaults.idle_timeout),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Matcher {
     pub uri: Option<StrMatch>,
     pub headers: Option<HashMap<String, StrMatch>>,
+    pub cookies: Option<HashMap<String, StrMatch>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -103,24 +211,78 @@ pub enum StrMatch {
     Regexp(String),
     Prefix(String),
     Exact(String),
+    /// Matches on whether the value exists at all, not supported for uri
+    Present(bool),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Auth {
     #[serde(rename = "type")]
-    pub auth_type: String,
+    pub auth_type: AuthType,
+    /// Names of the identities allowed to access the route, all identities are allowed if not set
     pub allowed_identities: Option<Vec<String>>,
-    pub config: Option<ForwardAuthConfig>,
+    /// Auth type specific configuration
+    pub config: Option<YamlValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthType {
+    BasicAuth,
+    JwtAuth,
+    HmacAuth,
+    ForwardAuth,
+    KeyAuth,
+    MtlsAuth,
+    OpenidConnect,
+}
+
+impl AuthType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthType::BasicAuth => "basic_auth",
+            AuthType::JwtAuth => "jwt_auth",
+            AuthType::HmacAuth => "hmac_auth",
+            AuthType::ForwardAuth => "forward_auth",
+            AuthType::KeyAuth => "key_auth",
+            AuthType::MtlsAuth => "mtls_auth",
+            AuthType::OpenidConnect => "openid_connect",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ForwardAuthConfig {
+    /// Cluster of the authorization service
     pub cluster: String,
+    /// Path the subrequest is sent to
     pub path: String,
+    /// Request headers sent along with the subrequest
+    #[serde(default)]
     pub headers_to_forward: Vec<String>,
+    /// Headers of a 2xx authorization response which are copied to the upstream request
+    #[serde(default)]
+    pub upstream_headers: Vec<String>,
+    /// Timeout of the whole subrequest
+    #[serde(default = "default_forward_auth_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Allows and 401/403 denies are cached for the ttl if set
+    #[serde(default, with = "humantime_serde")]
+    pub cache_ttl: Option<Duration>,
+    /// Maximum number of cached decisions
+    #[serde(default = "default_forward_auth_cache_size")]
+    pub cache_size: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn default_forward_auth_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+fn default_forward_auth_cache_size() -> usize {
+    10000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Plugin {
     pub name: String,
     pub config: Option<YamlValue>,
@@ -133,6 +295,9 @@ pub struct Cluster {
     pub lb_policy: LbPolicy,
     pub config: Option<YamlValue>,
     pub health_checks: Option<Vec<HealthCheck>>,
+    /// Default timeouts of the routes forwarding to this cluster
+    #[serde(flatten)]
+    pub timeouts: Timeouts,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -3,3 +3,6 @@ pub mod lb;
 
 /// Module for plugin system implementation
 pub mod plugin;
+
+/// Module for headers added to all the responses of a request
+pub mod response_headers;
@@ -1,13 +1,117 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+
 use async_trait::async_trait;
 use bytes::Bytes;
+use http::{header, Extensions};
 use matchit::Params;
+use once_cell::sync::OnceCell;
+use openssl::{hash::MessageDigest, x509::X509Ref};
 use pingora::{http::ResponseHeader, prelude::*};
-use regex::Captures;
+use regex::{Captures, Regex};
 
 /// Context for plugin execution
 #[derive(Default)]
 pub struct PluginCtx {
+    /// Name of the matched route
+    pub route: Option<String>,
     pub route_params: Option<RouteParams>,
+    /// Real ip of the client, set when it's resolved from headers of a trusted proxy
+    pub client_ip: Option<IpAddr>,
+    /// Name of the identity the request is authenticated as
+    pub identity: Option<String>,
+    /// Verified certificate of the client, set on mutual TLS listeners
+    pub client_cert: Option<Arc<ClientCert>>,
+    /// Id of the request used to correlate logs, set by the request_id plugin
+    pub request_id: Option<String>,
+    /// Per request state of plugins, keyed by type
+    pub extensions: Extensions,
+    /// Cookies of the request, parsed on first access
+    cookies: OnceCell<HashMap<String, String>>,
+}
+
+impl PluginCtx {
+    /// Returns the value of the request cookie `name`
+    ///
+    /// The `Cookie` headers are parsed only once per request, `req` must always be the header of
+    /// the current request.
+    pub fn cookie(&self, req: &RequestHeader, name: &str) -> Option<&str> {
+        self.cookies
+            .get_or_init(|| parse_cookies(req))
+            .get(name)
+            .map(|v| v.as_str())
+    }
+}
+
+/// Parses all `Cookie` headers of the request, the first occurrence of a name wins
+fn parse_cookies(req: &RequestHeader) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for value in req.headers.get_all(header::COOKIE) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for pair in value.split(';') {
+            if let Some((k, v)) = pair.split_once('=') {
+                cookies
+                    .entry(k.trim().to_string())
+                    .or_insert_with(|| v.trim().trim_matches('"').to_string());
+            }
+        }
+    }
+    cookies
+}
+
+/// Details of a client certificate
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    /// Subject in certificate order, e.g. `C=US,O=Acme,CN=client`
+    pub subject: String,
+    /// DNS names, URIs, emails and ip addresses of the subject alternative names
+    pub sans: Vec<String>,
+    /// Lowercase hex of the SHA-256 digest of the certificate
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    pub fn new(cert: &X509Ref) -> Self {
+        let subject = cert
+            .subject_name()
+            .entries()
+            .map(|entry| {
+                let name = entry.object().nid().short_name().unwrap_or("?");
+                let value = entry
+                    .data()
+                    .as_utf8()
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                format!("{}={}", name, value)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let sans = cert
+            .subject_alt_names()
+            .iter()
+            .flatten()
+            .filter_map(|name| {
+                if let Some(v) = name.dnsname().or(name.uri()).or(name.email()) {
+                    return Some(v.to_string());
+                }
+                match name.ipaddress()? {
+                    ip if ip.len() == 4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                    ip => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+                }
+                .map(|ip| ip.to_string())
+            })
+            .collect();
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect())
+            .unwrap_or_default();
+        Self {
+            subject,
+            sans,
+            fingerprint,
+        }
+    }
 }
 
 /// Main trait for plugins, defining various filter methods
@@ -91,15 +195,30 @@ pub trait Plugin: Send + Sync {
     /// * `_body` - Mutable reference to an optional Bytes containing the body chunk
     /// * `_end_of_stream` - Boolean indicating if this is the last chunk
     /// * `_ctx` - Mutable reference to the plugin context
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(delay))` to wait `delay` after the chunk is sent, e.g. to throttle the response
+    /// * `Ok(None)` to send the next chunk right away
     fn response_body_filter(
         &self,
         _session: &mut Session,
         _body: &mut Option<Bytes>,
         _end_of_stream: bool,
         _ctx: &mut PluginCtx,
-    ) -> Result<()> {
-        Ok(())
+    ) -> Result<Option<Duration>> {
+        Ok(None)
     }
+
+    /// Called once the request is done, whether the response was sent successfully or a fatal
+    /// error terminated it
+    ///
+    /// # Arguments
+    ///
+    /// * `_session` - Mutable reference to the current session
+    /// * `_e` - The error which terminated the request, if any
+    /// * `_ctx` - Mutable reference to the plugin context
+    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, _ctx: &mut PluginCtx) {}
 }
 
 /// Represents the parameters extracted from a route match
@@ -114,25 +233,66 @@ pub struct RouteParams {
     /// For regex matches, this includes all captured groups.
     /// For path matches, this includes all path segments that were matched as parameters.
     params: Vec<String>,
+    /// Names of the captured parameters, aligned with `params`
+    ///
+    /// Unnamed regex groups have no name.
+    names: Vec<Option<String>>,
 }
 
 impl RouteParams {
-    pub fn new_caps(caps: &Captures) -> Self {
-        Self {
-            params: caps
-                .iter()
-                .filter_map(|s| s.map(|ss| ss.as_str().to_string()))
-                .collect(),
-        }
+    pub fn new_caps(re: &Regex, caps: &Captures) -> Self {
+        let (names, params) = re
+            .capture_names()
+            .zip(caps.iter())
+            .filter_map(|(name, m)| m.map(|m| (name.map(str::to_string), m.as_str().to_string())))
+            .unzip();
+        Self { params, names }
     }
 
     pub fn new_params(params: &Params) -> Self {
-        Self {
-            params: params.iter().map(|(_, v)| v.to_string()).collect(),
-        }
+        let (names, params) = params
+            .iter()
+            .map(|(k, v)| (Some(k.to_string()), v.to_string()))
+            .unzip();
+        Self { params, names }
     }
 
     pub fn get(&self, idx: usize) -> Option<&str> {
         self.params.get(idx).map(|s| s.as_str())
     }
+
+    /// Iterates over the parameters along with their names
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&str>, &str)> {
+        self.names
+            .iter()
+            .map(|n| n.as_deref())
+            .zip(self.params.iter().map(|p| p.as_str()))
+    }
+
+    /// Returns the parameter captured under `name`
+    pub fn get_by_name(&self, name: &str) -> Option<&str> {
+        self.names
+            .iter()
+            .position(|n| n.as_deref() == Some(name))
+            .and_then(|idx| self.get(idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_of_all_headers_are_parsed_first_wins() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header(header::COOKIE, "a=1; b=\"two\"; junk")
+            .unwrap();
+        req.append_header(header::COOKIE, " a=3 ;c= x=y ").unwrap();
+        let ctx = PluginCtx::default();
+        assert_eq!(ctx.cookie(&req, "a"), Some("1"));
+        assert_eq!(ctx.cookie(&req, "b"), Some("two"));
+        assert_eq!(ctx.cookie(&req, "c"), Some("x=y"));
+        assert_eq!(ctx.cookie(&req, "junk"), None);
+        assert_eq!(ctx.cookie(&req, "d"), None);
+    }
 }
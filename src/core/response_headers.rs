@@ -0,0 +1,65 @@
+use std::any::Any;
+
+use async_trait::async_trait;
+use http::{HeaderName, HeaderValue};
+use pingora::{
+    http::ResponseHeader,
+    modules::http::{HttpModule, HttpModuleBuilder, Module},
+    prelude::*,
+};
+
+/// Headers added to the response of the current request, whether it comes from the upstream or
+/// is generated by the gateway, e.g. a 404 or the rejection of a plugin
+///
+/// Headers the response already carries are kept, so plugins setting the same headers in their
+/// response filter take precedence.
+#[derive(Default)]
+pub struct ResponseHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ResponseHeaders {
+    /// Sets the headers the response doesn't carry yet
+    pub fn apply(&self, resp: &mut ResponseHeader) -> Result<()> {
+        for (name, value) in &self.headers {
+            if !resp.headers.contains_key(name) {
+                resp.insert_header(name.clone(), value.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HttpModule for ResponseHeaders {
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<()> {
+        self.apply(resp)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub struct ResponseHeadersBuilder;
+
+impl HttpModuleBuilder for ResponseHeadersBuilder {
+    fn init(&self) -> Module {
+        Box::new(ResponseHeaders::default())
+    }
+}
+
+/// Adds a header to the response of the current request, see [`ResponseHeaders`]
+pub fn add_response_header(session: &mut Session, name: HeaderName, value: HeaderValue) {
+    if let Some(headers) = session.downstream_modules_ctx.get_mut::<ResponseHeaders>() {
+        headers.headers.push((name, value));
+    }
+}
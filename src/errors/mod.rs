@@ -1,5 +1,6 @@
 use crate::{
     builder::errors::BuilderError, clusters::errors::ClusterError, config::errors::ConfigError,
+    tls::errors::TlsError,
 };
 use pingora::BError;
 use snafu::Snafu;
@@ -11,11 +12,18 @@ pub enum AppError {
     #[snafu(display("Cluster error: {}", source))]
     Cluster { source: ClusterError },
     #[snafu(display("Config error: {}", source))]
-    Config { source: ConfigError },
+    Config {
+        #[snafu(source(from(ConfigError, Box::new)))]
+        source: Box<ConfigError>,
+    },
     #[snafu(display("Builder error: {}", source))]
     Builder { source: BuilderError },
     #[snafu(display("Pingora error: {}", source))]
     Pingora { source: BError },
     #[snafu(display("Validation error: {}", source))]
     Validation { source: ValidationErrors },
+    #[snafu(display("Tls error: {}", source))]
+    Tls { source: TlsError },
+    #[snafu(display("Invalid argument: {}", msg))]
+    InvalidArgument { msg: String },
 }
@@ -1,3 +1,4 @@
+pub mod auth;
 pub mod builder;
 pub mod clusters;
 pub mod config;
@@ -5,4 +6,5 @@ pub mod core;
 pub mod errors;
 pub mod plugins;
 pub mod proxy;
+pub mod tls;
 pub mod utils;
@@ -2,19 +2,22 @@ use std::{path::PathBuf, sync::Arc};
 
 use clap::Parser;
 use penguin::{
-    builder::{build_plugin_list, init_discovery_providers, init_routes},
+    auth::build_identities,
+    builder::{build_consumer_plugins, build_plugin_list, init_discovery_providers, init_routes},
     clusters::ClusterManager,
     config::{
-        args::{Args, Command},
+        args::{Args, Command, RouteArgs},
         def::{Config, Listener, Service as ServiceConf},
         load_config,
     },
+    core::plugin::PluginCtx,
     errors::*,
     proxy::Proxy,
+    tls::{build_tls_settings, CertReloader},
 };
 use pingora::{
-    prelude::*, proxy::http_proxy_service_with_name, server::configuration::ServerConf,
-    services::Service as PingoraServiceTrait,
+    http::RequestHeader, prelude::*, proxy::http_proxy_service_with_name,
+    server::configuration::ServerConf, services::Service as PingoraServiceTrait,
 };
 use snafu::ResultExt;
 use validator::Validate;
@@ -31,6 +34,10 @@ fn main() -> Result<(), AppError> {
             load_and_validate_config(args.config)?;
             Ok(())
         }
+        Command::Route(route_args) => {
+            let config = load_and_validate_config(args.config)?;
+            show_route(config, route_args)
+        }
         Command::Run => {
             let config = load_and_validate_config(args.config)?;
             // init discovery providers
@@ -43,7 +50,11 @@ fn main() -> Result<(), AppError> {
             // combine them into a Proxy object(which is an implementation of Pingora ProxyHttp Trait)
             // create a pingora service based on the Proxy object
             // add the service to the pingora server
+            let identities = build_identities(config.identities);
+            let consumer_plugins =
+                Arc::new(build_consumer_plugins(&identities).context(BuilderSnafu)?);
             let mut svcs = vec![];
+            let mut cert_reloader = CertReloader::default();
             for ServiceConf {
                 name,
                 server_conf,
@@ -53,14 +64,25 @@ fn main() -> Result<(), AppError> {
                 clusters,
             } in config.services
             {
-                let routes = init_routes(routes).context(BuilderSnafu)?;
                 let clusters = ClusterManager::new(clusters, &resolvers).context(ClusterSnafu)?;
+                let routes = init_routes(routes, &identities, &clusters).context(BuilderSnafu)?;
                 let global_plugins = build_plugin_list(plugins).context(BuilderSnafu)?;
-                let proxy = Proxy::new(routes, clusters, global_plugins);
-                let svc =
-                    create_service(name, server_conf, listeners, proxy).context(PingoraSnafu)?;
+                let client_certs = listeners
+                    .iter()
+                    .any(|l| l.ssl_config.as_ref().is_some_and(|c| c.client_ca.is_some()));
+                let proxy = Proxy::new(
+                    routes,
+                    clusters,
+                    global_plugins,
+                    consumer_plugins.clone(),
+                    client_certs,
+                );
+                let svc = create_service(name, server_conf, listeners, proxy, &mut cert_reloader)?;
                 svcs.push(svc);
             }
+            if !cert_reloader.is_empty() {
+                svcs.push(Box::new(background_service("cert reloader", cert_reloader)));
+            }
             server.add_services(svcs);
             // run the server
             server.run_forever();
@@ -73,14 +95,18 @@ fn create_service(
     server_conf: Option<ServerConf>,
     listeners: Vec<Listener>,
     proxy: Proxy,
-) -> Result<Box<dyn PingoraServiceTrait>> {
+    cert_reloader: &mut CertReloader,
+) -> Result<Box<dyn PingoraServiceTrait>, AppError> {
     let mut svc =
         http_proxy_service_with_name(&Arc::new(server_conf.unwrap_or_default()), proxy, &name);
     for listener in listeners {
         let addr = listener.address.to_string();
         match listener.ssl_config {
             Some(ssl_config) => {
-                svc.add_tls(&addr, &ssl_config.cert_path, &ssl_config.key_path)?;
+                let (settings, watcher) =
+                    build_tls_settings(&listener.name, ssl_config).context(TlsSnafu)?;
+                svc.add_tls_with_settings(&addr, None, settings);
+                cert_reloader.add(watcher);
             }
             None => {
                 svc.add_tcp(&addr);
@@ -90,6 +116,58 @@ fn create_service(
     Ok(Box::new(svc))
 }
 
+/// Prints the route, plugin chain and cluster that a request would be dispatched to
+fn show_route(config: Config, args: RouteArgs) -> Result<(), AppError> {
+    let mut req = RequestHeader::build(args.method.as_str(), args.path.as_bytes(), None)
+        .context(PingoraSnafu)?;
+    if let Some(host) = args.host {
+        req.insert_header("host", host).context(PingoraSnafu)?;
+    }
+    for header in args.headers {
+        let (k, v) = header
+            .split_once(':')
+            .ok_or_else(|| AppError::InvalidArgument {
+                msg: format!("header must be in the form of key:value, got {}", header),
+            })?;
+        req.append_header(k.trim().to_string(), v.trim())
+            .context(PingoraSnafu)?;
+    }
+    let identities = build_identities(config.identities);
+    let resolvers = init_discovery_providers(&config.discovery_providers).context(BuilderSnafu)?;
+    for svc in config.services {
+        if args.service.as_ref().is_some_and(|name| *name != svc.name) {
+            continue;
+        }
+        let clusters = ClusterManager::new(svc.clusters, &resolvers).context(ClusterSnafu)?;
+        let matcher = init_routes(svc.routes, &identities, &clusters).context(BuilderSnafu)?;
+        let Some((params, ppl)) = matcher.match_request(&req, &PluginCtx::default()) else {
+            println!("service: {}, no route matched", svc.name);
+            continue;
+        };
+        let plugins = svc
+            .plugins
+            .iter()
+            .flatten()
+            .map(|pl| pl.name.as_str())
+            .chain(ppl.plugin_names().iter().map(|name| name.as_str()))
+            .collect::<Vec<_>>();
+        let params = params
+            .iter()
+            .enumerate()
+            .map(|(idx, (name, value))| match name {
+                Some(name) => format!("{}={}", name, value),
+                None => format!("{}={}", idx, value),
+            })
+            .collect::<Vec<_>>();
+        println!("service: {}", svc.name);
+        println!("  route: {}", ppl.name());
+        println!("  params: [{}]", params.join(", "));
+        println!("  plugins: [{}]", plugins.join(", "));
+        println!("  cluster: {}", ppl.cluster());
+    }
+    Ok(())
+}
+
 fn load_and_validate_config(path: PathBuf) -> Result<Config, AppError> {
     let config = load_config(path.as_path().to_str().unwrap()).context(ConfigSnafu)?;
     config.validate().context(ValidationSnafu)?;
@@ -1,8 +1,14 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use http::StatusCode;
-use pingora::prelude::*;
+use log::warn;
+use pingora::{http::ResponseHeader, prelude::*};
 use pingora_limits::rate::Rate;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value as YamlValue;
@@ -11,8 +17,12 @@ use validator::{Validate, ValidationError};
 
 use crate::{
     core::plugin::{Plugin, PluginCtx},
-    plugins::{errors::*, PluginResult},
-    utils::send_response,
+    plugins::{
+        errors::*,
+        limit::{RateLimitStatus, Rejection, RejectionConf},
+        PluginResult,
+    },
+    utils::template::Template,
 };
 
 pub const CMS_RATE_PLUGIN_NAME: &str = "cms_rate";
@@ -24,6 +34,21 @@ pub struct CmsRateConf {
     #[serde(with = "humantime_serde")]
     #[validate(custom(function = "atleast_1_second"))]
     pub interval: Duration,
+    /// Template of the key requests are counted by, e.g. `${client_ip}` or
+    /// `${identity}:${header.x-tenant}`. Requests are counted by path if not set
+    pub key: Option<String>,
+    /// Whether to send the `X-RateLimit-*` and `Retry-After` headers
+    #[serde(default = "default_headers")]
+    pub headers: bool,
+    #[serde(default)]
+    pub rejection: RejectionConf,
+    /// Only logs the requests that would be rejected
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_headers() -> bool {
+    true
 }
 
 fn atleast_1_second(v: &Duration) -> Result<(), ValidationError> {
@@ -43,28 +68,108 @@ pub fn create_cms_rate_limiter(config: Option<YamlValue>) -> PluginResult<Box<dy
     cfg.validate().context(ValidateErrSnafu {
         name: CMS_RATE_PLUGIN_NAME.to_string(),
     })?;
+    let key = Template::parse(cfg.key.as_deref().unwrap_or("${path}")).context(TemplateSnafu {
+        name: CMS_RATE_PLUGIN_NAME.to_string(),
+    })?;
     let r = Arc::new(Rate::new(cfg.interval));
     Ok(Box::new(PerRoutePlugin {
         total: cfg.total,
+        key,
         r,
+        window: Arc::new(Window::new(cfg.interval)),
+        headers: cfg.headers,
+        rejection: Arc::new(Rejection::new(cfg.rejection, CMS_RATE_PLUGIN_NAME)?),
+        dry_run: cfg.dry_run,
     }))
 }
 
+/// Tracks the start of the current window of [`Rate`], which starts a new window on the first
+/// observation after the interval elapsed
+struct Window {
+    origin: Instant,
+    interval_ms: u64,
+    /// Start of the current window in ms since `origin`
+    started_at: AtomicU64,
+}
+
+impl Window {
+    fn new(interval: Duration) -> Self {
+        Self {
+            origin: Instant::now(),
+            interval_ms: interval.as_millis() as u64,
+            started_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the seconds until the current window ends, must be called on every observation
+    fn reset_after(&self) -> u64 {
+        let now = self.origin.elapsed().as_millis() as u64;
+        let mut started_at = self.started_at.load(Ordering::Acquire);
+        if now - started_at >= self.interval_ms {
+            started_at = match self.started_at.compare_exchange(
+                started_at,
+                now,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => now,
+                Err(other) => other,
+            };
+        }
+        (self.interval_ms - now.saturating_sub(started_at).min(self.interval_ms)).div_ceil(1000)
+    }
+}
+
 #[derive(Clone)]
 pub struct PerRoutePlugin {
     total: isize,
+    key: Template,
     r: Arc<Rate>,
+    window: Arc<Window>,
+    headers: bool,
+    rejection: Arc<Rejection>,
+    dry_run: bool,
 }
 
 #[async_trait]
 impl Plugin for PerRoutePlugin {
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut PluginCtx) -> Result<bool> {
-        let path = session.req_header().uri.path();
-        let nc = self.r.observe(&path, 1);
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let key = self.key.render(session, ctx);
+        let nc = self.r.observe(&key, 1);
+        let remaining = (self.total - nc).max(0) as u64;
+        let reset = self.window.reset_after();
+        let status = RateLimitStatus {
+            limit: self.total as u64,
+            remaining,
+            reset,
+            retry_after: if remaining == 0 { reset } else { 0 },
+        };
         if nc > self.total {
-            send_response(session, StatusCode::TOO_MANY_REQUESTS, None, None, None).await?;
-            return Ok(true);
+            if !self.dry_run {
+                let headers = self.headers.then_some(status);
+                self.rejection.send(session, headers, None).await?;
+                return Ok(true);
+            }
+            warn!(
+                "cms_rate would reject request, key: {}, count: {}, total: {}",
+                key, nc, self.total
+            );
+        }
+        if self.headers {
+            status.record(ctx);
         }
         Ok(false)
     }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if !self.headers {
+            return Ok(());
+        }
+        RateLimitStatus::apply(ctx, upstream_response)
+    }
 }
@@ -0,0 +1,334 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use pingora::{http::ResponseHeader, prelude::*};
+use regex::RegexSet;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    core::{
+        plugin::{Plugin, PluginCtx},
+        response_headers::add_response_header,
+    },
+    plugins::{errors::*, PluginResult},
+    utils::send_response,
+};
+
+pub const CORS_PLUGIN_NAME: &str = "cors";
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "no_wildcard_with_credentials"))]
+pub struct CorsConf {
+    /// Origins allowed exactly, e.g. `https://example.com`, `*` allows any origin
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    /// Regexes the whole origin must match, e.g. `https://.*\.example\.com`
+    #[serde(default)]
+    pub allow_origins_regex: Vec<String>,
+    #[serde(default = "default_allow_methods")]
+    pub allow_methods: Vec<String>,
+    /// Request headers allowed in the actual request, the ones asked by the preflight are
+    /// allowed if empty
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Response headers exposed to the browser
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache the preflight response
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+fn default_allow_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn no_wildcard_with_credentials(cfg: &CorsConf) -> Result<(), ValidationError> {
+    if cfg.allow_credentials && cfg.allow_origins.iter().any(|o| o == "*") {
+        return Err(ValidationError::new(
+            "allow_origins must not contain * when allow_credentials is set",
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_cors_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: CORS_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: CorsConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: CORS_PLUGIN_NAME.to_string(),
+    })?;
+    Ok(Box::new(CorsPlugin::new(cfg)?))
+}
+
+/// Handles cross-origin requests, answering the preflights directly
+pub struct CorsPlugin {
+    /// Any origin is allowed and answered with `*`, the responses don't vary by origin
+    any_origin: bool,
+    origins: Vec<String>,
+    origins_regex: RegexSet,
+    allow_methods: String,
+    allow_headers: Option<String>,
+    expose_headers: Option<String>,
+    allow_credentials: bool,
+    max_age: Option<String>,
+}
+
+impl CorsPlugin {
+    fn new(cfg: CorsConf) -> PluginResult<Self> {
+        cfg.validate().context(ValidateErrSnafu {
+            name: CORS_PLUGIN_NAME.to_string(),
+        })?;
+        let origins_regex = RegexSet::new(
+            cfg.allow_origins_regex
+                .iter()
+                .map(|re| format!("^(?:{})$", re)),
+        )
+        .map_err(|e| e.into())
+        .context(SpecificErrSnafu {
+            name: CORS_PLUGIN_NAME.to_string(),
+        })?;
+        let any_origin = cfg.allow_origins.iter().any(|o| o == "*");
+        let join = |values: &[String]| (!values.is_empty()).then(|| values.join(", "));
+        Ok(Self {
+            any_origin,
+            origins: cfg.allow_origins,
+            origins_regex,
+            allow_methods: cfg.allow_methods.join(", "),
+            allow_headers: join(&cfg.allow_headers),
+            expose_headers: join(&cfg.expose_headers),
+            allow_credentials: cfg.allow_credentials,
+            max_age: cfg.max_age.map(|d| d.as_secs().to_string()),
+        })
+    }
+
+    /// Returns the `Access-Control-Allow-Origin` value for the origin, if it's allowed
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.any_origin {
+            return Some("*");
+        }
+        (self.origins.iter().any(|o| o == origin) || self.origins_regex.is_match(origin))
+            .then_some(origin)
+    }
+
+    /// Returns the CORS headers of the responses to actual requests from `origin`
+    fn response_headers(&self, origin: Option<&str>) -> Vec<(HeaderName, String)> {
+        let mut headers = vec![];
+        let Some(allow_origin) = origin.and_then(|origin| self.allow_origin(origin)) else {
+            return headers;
+        };
+        headers.push((
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            allow_origin.to_string(),
+        ));
+        if self.allow_credentials {
+            headers.push((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_string()));
+        }
+        if let Some(expose_headers) = &self.expose_headers {
+            headers.push((
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                expose_headers.clone(),
+            ));
+        }
+        headers
+    }
+
+    async fn preflight(&self, session: &mut Session, origin: &str) -> Result<()> {
+        let req = session.req_header();
+        let mut headers = HashMap::new();
+        if let Some(allow_origin) = self.allow_origin(origin) {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN.to_string(),
+                allow_origin.to_string(),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS.to_string(),
+                self.allow_methods.clone(),
+            );
+            let allow_headers = self.allow_headers.clone().or_else(|| {
+                req.headers
+                    .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+            });
+            if let Some(allow_headers) = allow_headers {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS.to_string(),
+                    allow_headers,
+                );
+            }
+            if self.allow_credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS.to_string(),
+                    "true".to_string(),
+                );
+            }
+            if let Some(max_age) = &self.max_age {
+                headers.insert(header::ACCESS_CONTROL_MAX_AGE.to_string(), max_age.clone());
+            }
+        }
+        let mut vary = vec![
+            header::ACCESS_CONTROL_REQUEST_METHOD.as_str(),
+            header::ACCESS_CONTROL_REQUEST_HEADERS.as_str(),
+        ];
+        if !self.any_origin {
+            vary.insert(0, header::ORIGIN.as_str());
+        }
+        headers.insert(header::VARY.to_string(), vary.join(", "));
+        send_response(session, StatusCode::NO_CONTENT, None, None, Some(headers)).await
+    }
+}
+
+/// Adds `value` to the `Vary` header of the response unless it's already there
+fn add_vary(resp: &mut ResponseHeader, value: &str) -> Result<()> {
+    let present = resp
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(value));
+    if !present {
+        resp.append_header(header::VARY, value.to_string())?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Plugin for CorsPlugin {
+    async fn request_filter(&self, session: &mut Session, _ctx: &mut PluginCtx) -> Result<bool> {
+        let req = session.req_header();
+        let origin = req
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if req.method == Method::OPTIONS
+            && req
+                .headers
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            if let Some(origin) = origin {
+                self.preflight(session, &origin).await?;
+                return Ok(true);
+            }
+        }
+        // the responses generated by the gateway, e.g. the rejections of other plugins, skip
+        // the response filter but must still be readable by the browser
+        if !self.any_origin {
+            add_response_header(
+                session,
+                header::VARY,
+                HeaderValue::from_static(header::ORIGIN.as_str()),
+            );
+        }
+        for (name, value) in self.response_headers(origin.as_deref()) {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                add_response_header(session, name, value);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        _ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        // Responses to requests with and without an allowed origin differ, so caches must key
+        // them by origin
+        if !self.any_origin {
+            add_vary(upstream_response, header::ORIGIN.as_str())?;
+        }
+        let origin = session
+            .req_header()
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok());
+        for (name, value) in self.response_headers(origin) {
+            upstream_response.insert_header(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(cfg: &str) -> PluginResult<CorsPlugin> {
+        CorsPlugin::new(serde_yaml::from_str(cfg).unwrap())
+    }
+
+    #[test]
+    fn origins_are_matched_exactly_or_by_whole_regex() {
+        let cors = plugin(
+            r#"
+            allow_origins: ["https://example.com"]
+            allow_origins_regex: ['https://[a-z]+\.example\.com']
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            cors.allow_origin("https://example.com"),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            cors.allow_origin("https://api.example.com"),
+            Some("https://api.example.com")
+        );
+        assert_eq!(cors.allow_origin("https://example.com.evil.io"), None);
+        assert_eq!(cors.allow_origin("https://api.example.com.evil.io"), None);
+        assert_eq!(
+            cors.allow_origin("https://evil.io/https://a.example.com"),
+            None
+        );
+        assert_eq!(cors.allow_origin("http://example.com"), None);
+    }
+
+    #[test]
+    fn any_origin_is_answered_with_a_wildcard() {
+        let cors = plugin(r#"allow_origins: ["*"]"#).unwrap();
+        assert_eq!(cors.allow_origin("https://example.com"), Some("*"));
+        assert!(plugin("{allow_origins: [\"*\"], allow_credentials: true}").is_err());
+    }
+
+    #[test]
+    fn response_headers_of_allowed_origins() {
+        let cors = plugin(
+            r#"
+            allow_origins: ["https://example.com"]
+            allow_credentials: true
+            expose_headers: [x-a, x-b]
+            "#,
+        )
+        .unwrap();
+        let headers = cors.response_headers(Some("https://example.com"));
+        assert_eq!(
+            headers,
+            [
+                (
+                    header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                    "https://example.com".to_string()
+                ),
+                (header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true".to_string()),
+                (
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    "x-a, x-b".to_string()
+                ),
+            ]
+        );
+        assert!(cors.response_headers(Some("https://other.com")).is_empty());
+        assert!(cors.response_headers(None).is_empty());
+    }
+}
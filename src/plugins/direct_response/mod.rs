@@ -0,0 +1,168 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue, StatusCode};
+use log::warn;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::prelude::*;
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{errors::*, PluginResult},
+    utils::{send_response, template::Template},
+};
+
+pub const DIRECT_RESPONSE_PLUGIN_NAME: &str = "direct_response";
+
+pub fn create_direct_response_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+    })?;
+    let config: DirectResponseConfigRaw = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+    })?;
+    let body = match (config.body, config.body_file) {
+        (Some(body), None) => body.into_bytes(),
+        (None, Some(path)) => std::fs::read(&path).context(ReadFileSnafu {
+            path,
+            name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+        })?,
+        (None, None) => vec![],
+        (Some(_), Some(_)) => {
+            return Err(PluginError::SpecificErr {
+                source: "body and body_file are mutually exclusive".into(),
+                name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+            })
+        }
+    };
+    let body = if config.template {
+        let body = String::from_utf8(body)
+            .map_err(|e| e.utf8_error().into())
+            .context(SpecificErrSnafu {
+                name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+            })?;
+        let body = Template::parse(&body).context(TemplateSnafu {
+            name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+        })?;
+        // static bodies are rendered once here instead of on every request
+        match body.as_static() {
+            Some(body) => Body::Static(Bytes::from(body)),
+            None => Body::Template(body),
+        }
+    } else {
+        Body::Static(Bytes::from(body))
+    };
+    let mut headers = vec![];
+    for (k, v) in config.headers.unwrap_or_default() {
+        let name = HeaderName::from_str(&k)
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+            })?;
+        let tpl = Template::parse(&v).context(TemplateSnafu {
+            name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+        })?;
+        if let Some(value) = tpl.as_static() {
+            validate_header_value(&value)?;
+        }
+        headers.push((name.to_string(), tpl));
+    }
+    if let Some(content_type) = &config.content_type {
+        validate_header_value(content_type)?;
+    }
+    let config = DirectResponseConfig {
+        status_code: StatusCode::from_u16(config.status_code.unwrap_or(200))
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+            })?,
+        content_type: config.content_type,
+        body,
+        headers,
+    };
+    Ok(Box::new(DirectResponsePlugin {
+        config: Arc::new(config),
+    }))
+}
+
+fn validate_header_value(value: &str) -> PluginResult<()> {
+    HeaderValue::from_str(value)
+        .map(|_| ())
+        .map_err(|e| e.into())
+        .context(SpecificErrSnafu {
+            name: DIRECT_RESPONSE_PLUGIN_NAME.to_string(),
+        })
+}
+
+#[derive(Clone)]
+pub struct DirectResponsePlugin {
+    config: Arc<DirectResponseConfig>,
+}
+
+#[derive(Debug)]
+struct DirectResponseConfig {
+    status_code: StatusCode,
+    content_type: Option<String>,
+    body: Body,
+    headers: Vec<(String, Template)>,
+}
+
+#[derive(Debug)]
+enum Body {
+    Static(Bytes),
+    Template(Template),
+}
+
+#[derive(Debug, Deserialize)]
+struct DirectResponseConfigRaw {
+    status_code: Option<u16>,
+    content_type: Option<String>,
+    body: Option<String>,
+    body_file: Option<String>,
+    /// Whether the body is a template, it's sent verbatim otherwise
+    #[serde(default = "default_template")]
+    template: bool,
+    headers: Option<HashMap<String, String>>,
+}
+
+fn default_template() -> bool {
+    true
+}
+
+#[async_trait]
+impl Plugin for DirectResponsePlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let body = match &self.config.body {
+            Body::Static(body) => body.clone(),
+            Body::Template(body) => Bytes::from(body.render(session, ctx)),
+        };
+        let headers = self
+            .config
+            .headers
+            .iter()
+            .map(|(k, v)| (k.clone(), v.render(session, ctx)))
+            .filter(|(k, v)| {
+                let valid = HeaderValue::from_str(v).is_ok();
+                if !valid {
+                    warn!(
+                        "direct_response skipped invalid value of header {}: {:?}",
+                        k, v
+                    );
+                }
+                valid
+            })
+            .collect::<HashMap<_, _>>();
+        send_response(
+            session,
+            self.config.status_code,
+            self.config.content_type.as_deref(),
+            Some(body),
+            Some(headers),
+        )
+        .await?;
+        Ok(true)
+    }
+}
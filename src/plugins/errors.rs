@@ -2,6 +2,8 @@ use serde_yaml::Error as YamlError;
 use snafu::prelude::*;
 use validator::ValidationErrors;
 
+use crate::utils::errors::TemplateError;
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
 pub enum PluginError {
@@ -16,6 +18,14 @@ pub enum PluginError {
     YamlErr { source: YamlError, name: String },
     #[snafu(display("Lack plugin config: {}", name))]
     LackPluginConfig { name: String },
+    #[snafu(display("Invalid template in plugin: {}, error: {}", name, source))]
+    Template { source: TemplateError, name: String },
+    #[snafu(display("Failed to read file {} for plugin: {}, error: {}", path, name, source))]
+    ReadFile {
+        source: std::io::Error,
+        path: String,
+        name: String,
+    },
     #[snafu(display("Specific error: {}, name: {}", source, name))]
     SpecificErr {
         source: Box<dyn std::error::Error>,
@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue, StatusCode};
+use pingora::prelude::*;
+use rand::Rng;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{errors::*, PluginResult},
+    utils::send_response,
+};
+
+pub const FAULT_INJECTION_PLUGIN_NAME: &str = "fault_injection";
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "delay_or_abort"))]
+pub struct FaultInjectionConf {
+    #[validate(nested)]
+    pub delay: Option<DelayConf>,
+    #[validate(nested)]
+    pub abort: Option<AbortConf>,
+    /// Only requests carrying this header are affected, all requests are if not set
+    pub header: Option<HeaderCondition>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "fixed_or_range"))]
+pub struct DelayConf {
+    /// Fixed delay
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+    /// Bounds of a random delay
+    #[serde(default, with = "humantime_serde")]
+    pub min: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub max: Option<Duration>,
+    /// Percentage of the requests delayed
+    #[serde(default = "default_percentage")]
+    #[validate(range(min = 0.0, max = 100.0))]
+    pub percentage: f64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AbortConf {
+    #[validate(range(min = 200, max = 599))]
+    pub status: u16,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    /// Percentage of the requests aborted
+    #[serde(default = "default_percentage")]
+    #[validate(range(min = 0.0, max = 100.0))]
+    pub percentage: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeaderCondition {
+    pub name: String,
+    /// Value the header must have, any value matches if not set
+    pub value: Option<String>,
+}
+
+fn default_percentage() -> f64 {
+    100.0
+}
+
+fn delay_or_abort(cfg: &FaultInjectionConf) -> Result<(), ValidationError> {
+    if cfg.delay.is_none() && cfg.abort.is_none() {
+        return Err(ValidationError::new("either delay or abort must be set"));
+    }
+    Ok(())
+}
+
+fn fixed_or_range(cfg: &DelayConf) -> Result<(), ValidationError> {
+    match (cfg.duration, cfg.min, cfg.max) {
+        (Some(_), None, None) => Ok(()),
+        (None, Some(min), Some(max)) if min <= max => Ok(()),
+        _ => Err(ValidationError::new(
+            "delay needs either duration or min and max, with min <= max",
+        )),
+    }
+}
+
+pub fn create_fault_injection_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: FaultInjectionConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+    })?;
+    cfg.validate().context(ValidateErrSnafu {
+        name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+    })?;
+    let header = cfg
+        .header
+        .map(|h| {
+            HeaderName::try_from(h.name)
+                .map(|name| (name, h.value))
+                .map_err(|e| e.into())
+                .context(SpecificErrSnafu {
+                    name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+                })
+        })
+        .transpose()?;
+    if let Some(content_type) = cfg.abort.as_ref().and_then(|a| a.content_type.as_ref()) {
+        HeaderValue::from_str(content_type)
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+            })?;
+    }
+    let abort = cfg
+        .abort
+        .map(|abort| {
+            StatusCode::from_u16(abort.status)
+                .map(|status| Abort {
+                    status,
+                    body: abort.body.map(Bytes::from),
+                    content_type: abort.content_type,
+                    percentage: abort.percentage,
+                })
+                .map_err(|e| e.into())
+                .context(SpecificErrSnafu {
+                    name: FAULT_INJECTION_PLUGIN_NAME.to_string(),
+                })
+        })
+        .transpose()?;
+    Ok(Box::new(FaultInjectionPlugin {
+        delay: cfg.delay.map(|delay| Delay {
+            min: delay.duration.or(delay.min).unwrap_or_default(),
+            max: delay.duration.or(delay.max).unwrap_or_default(),
+            percentage: delay.percentage,
+        }),
+        abort,
+        header,
+    }))
+}
+
+struct Delay {
+    min: Duration,
+    max: Duration,
+    percentage: f64,
+}
+
+struct Abort {
+    status: StatusCode,
+    body: Option<Bytes>,
+    content_type: Option<String>,
+    percentage: f64,
+}
+
+/// Returns whether a request falls within the percentage
+fn hit(percentage: f64) -> bool {
+    rand::thread_rng().gen::<f64>() * 100.0 < percentage
+}
+
+/// Delays and/or aborts a percentage of the requests, to test how clients cope with faults
+pub struct FaultInjectionPlugin {
+    delay: Option<Delay>,
+    abort: Option<Abort>,
+    header: Option<(HeaderName, Option<String>)>,
+}
+
+#[async_trait]
+impl Plugin for FaultInjectionPlugin {
+    async fn request_filter(&self, session: &mut Session, _ctx: &mut PluginCtx) -> Result<bool> {
+        if let Some((name, value)) = &self.header {
+            let matched = session.req_header().headers.get_all(name).iter().any(|v| {
+                value
+                    .as_ref()
+                    .is_none_or(|value| v.as_bytes() == value.as_bytes())
+            });
+            if !matched {
+                return Ok(false);
+            }
+        }
+        if let Some(delay) = self.delay.as_ref().filter(|d| hit(d.percentage)) {
+            let duration = if delay.min == delay.max {
+                delay.min
+            } else {
+                rand::thread_rng().gen_range(delay.min..=delay.max)
+            };
+            tokio::time::sleep(duration).await;
+        }
+        if let Some(abort) = self.abort.as_ref().filter(|a| hit(a.percentage)) {
+            send_response(
+                session,
+                abort.status,
+                abort.content_type.as_deref(),
+                abort.body.clone(),
+                None,
+            )
+            .await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
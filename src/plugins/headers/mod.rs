@@ -0,0 +1,276 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use indexmap::IndexMap;
+use log::warn;
+use pingora::{http::ResponseHeader, prelude::*};
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{errors::*, PluginResult},
+    utils::template::Template,
+};
+
+pub const HEADERS_PLUGIN_NAME: &str = "headers";
+
+#[derive(Debug, Deserialize, Default)]
+pub struct HeadersConf {
+    /// Changes to the request sent upstream
+    #[serde(default)]
+    pub request: HeaderRulesConf,
+    /// Changes to the response sent downstream
+    #[serde(default)]
+    pub response: HeaderRulesConf,
+}
+
+/// Changes to a header map, applied in the order of the fields, and within a field in the
+/// order of the configuration
+///
+/// Values are templates, e.g. `${client_ip}` or `${route}`.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct HeaderRulesConf {
+    /// Renames headers, keeping their values
+    pub rename: IndexMap<String, String>,
+    pub remove: Vec<String>,
+    /// Replaces the values of headers
+    pub set: IndexMap<String, String>,
+    /// Sets headers which are not present
+    pub add: IndexMap<String, String>,
+    /// Adds a value to headers, keeping the existing ones
+    pub append: IndexMap<String, String>,
+}
+
+pub fn create_headers_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: HEADERS_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: HeadersConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: HEADERS_PLUGIN_NAME.to_string(),
+    })?;
+    Ok(Box::new(HeadersPlugin {
+        request: HeaderRules::new(cfg.request)?,
+        response: HeaderRules::new(cfg.response)?,
+    }))
+}
+
+fn parse_name(name: &str) -> PluginResult<HeaderName> {
+    HeaderName::from_str(name)
+        .map_err(|e| e.into())
+        .context(SpecificErrSnafu {
+            name: HEADERS_PLUGIN_NAME.to_string(),
+        })
+}
+
+fn parse_values(values: IndexMap<String, String>) -> PluginResult<Vec<(HeaderName, Template)>> {
+    values
+        .iter()
+        .map(|(name, value)| {
+            let value = Template::parse(value).context(TemplateSnafu {
+                name: HEADERS_PLUGIN_NAME.to_string(),
+            })?;
+            Ok((parse_name(name)?, value))
+        })
+        .collect()
+}
+
+/// Header maps of pingora keep the case of the names aside, so they must be changed through
+/// their own methods
+trait HeaderOps {
+    fn map(&self) -> &HeaderMap;
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+macro_rules! impl_header_ops {
+    ($t:ty) => {
+        impl HeaderOps for $t {
+            fn map(&self) -> &HeaderMap {
+                &self.headers
+            }
+
+            fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+                self.insert_header(name, value)
+            }
+
+            fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+                self.append_header(name, value).map(|_| ())
+            }
+
+            fn remove(&mut self, name: &HeaderName) {
+                self.remove_header(name);
+            }
+        }
+    };
+}
+
+impl_header_ops!(RequestHeader);
+impl_header_ops!(ResponseHeader);
+
+struct HeaderRules {
+    rename: Vec<(HeaderName, HeaderName)>,
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, Template)>,
+    add: Vec<(HeaderName, Template)>,
+    append: Vec<(HeaderName, Template)>,
+}
+
+impl HeaderRules {
+    fn new(cfg: HeaderRulesConf) -> PluginResult<Self> {
+        Ok(Self {
+            rename: cfg
+                .rename
+                .iter()
+                .map(|(from, to)| Ok((parse_name(from)?, parse_name(to)?)))
+                .collect::<PluginResult<_>>()?,
+            remove: cfg
+                .remove
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<PluginResult<_>>()?,
+            set: parse_values(cfg.set)?,
+            add: parse_values(cfg.add)?,
+            append: parse_values(cfg.append)?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rename.is_empty()
+            && self.remove.is_empty()
+            && self.set.is_empty()
+            && self.add.is_empty()
+            && self.append.is_empty()
+    }
+
+    fn apply(
+        &self,
+        session: &Session,
+        ctx: &PluginCtx,
+        headers: &mut impl HeaderOps,
+    ) -> Result<()> {
+        for (from, to) in &self.rename {
+            let values: Vec<HeaderValue> = headers.map().get_all(from).iter().cloned().collect();
+            if values.is_empty() {
+                continue;
+            }
+            headers.remove(from);
+            headers.remove(to);
+            for value in values {
+                headers.append(to.clone(), value)?;
+            }
+        }
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            if let Some(value) = render(session, ctx, name, value) {
+                headers.insert(name.clone(), value)?;
+            }
+        }
+        for (name, value) in &self.add {
+            if headers.map().contains_key(name) {
+                continue;
+            }
+            if let Some(value) = render(session, ctx, name, value) {
+                headers.insert(name.clone(), value)?;
+            }
+        }
+        for (name, value) in &self.append {
+            if let Some(value) = render(session, ctx, name, value) {
+                headers.append(name.clone(), value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the value of a header, values which aren't valid in a header are skipped
+fn render(
+    session: &Session,
+    ctx: &PluginCtx,
+    name: &HeaderName,
+    value: &Template,
+) -> Option<HeaderValue> {
+    let rendered = value.render(session, ctx);
+    match HeaderValue::from_str(&rendered) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!(
+                "headers skipped invalid value of header {}: {:?}",
+                name, rendered
+            );
+            None
+        }
+    }
+}
+
+/// Adds, sets, appends, removes and renames the headers of requests and responses
+pub struct HeadersPlugin {
+    request: HeaderRules,
+    response: HeaderRules,
+}
+
+#[async_trait]
+impl Plugin for HeadersPlugin {
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if self.request.is_empty() {
+            return Ok(());
+        }
+        self.request.apply(session, ctx, upstream_request)
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if self.response.is_empty() {
+            return Ok(());
+        }
+        self.response.apply(session, ctx, upstream_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_keep_the_configured_order() {
+        let cfg: HeaderRulesConf = serde_yaml::from_str(
+            r#"
+            rename: {x-b: x-c, x-a: x-b}
+            set: {x-z: "1", x-a: "2", x-m: "3", x-b: "4"}
+            "#,
+        )
+        .unwrap();
+        let rules = HeaderRules::new(cfg).unwrap();
+        let renames: Vec<_> = rules
+            .rename
+            .iter()
+            .map(|(from, to)| (from.as_str(), to.as_str()))
+            .collect();
+        assert_eq!(renames, [("x-b", "x-c"), ("x-a", "x-b")]);
+        let set: Vec<_> = rules.set.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(set, ["x-z", "x-a", "x-m", "x-b"]);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let cfg: HeaderRulesConf = serde_yaml::from_str("remove: [\"x a\"]").unwrap();
+        assert!(HeaderRules::new(cfg).is_err());
+        let cfg: HeaderRulesConf = serde_yaml::from_str("set: {x-a: \"${nope}\"}").unwrap();
+        assert!(HeaderRules::new(cfg).is_err());
+    }
+}
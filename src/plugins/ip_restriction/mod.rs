@@ -0,0 +1,239 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderName, StatusCode};
+use ipnet::IpNet;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::prelude::*;
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{errors::*, PluginResult},
+    utils::{client_ip, send_response},
+};
+
+pub const IP_RESTRICTION_PLUGIN_NAME: &str = "ip_restriction";
+
+pub fn create_ip_restriction_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+    })?;
+    let config: IpRestrictionConfigRaw = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+    })?;
+    if config.allow.is_empty() && config.deny.is_empty() {
+        return Err(PluginError::SpecificErr {
+            source: "at least one of allow and deny is required".into(),
+            name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+        });
+    }
+    let config = IpRestrictionConfig {
+        allow: parse_cidrs(&config.allow)?,
+        deny: parse_cidrs(&config.deny)?,
+        trusted_proxies: parse_cidrs(&config.trusted_proxies)?,
+        real_ip_header: HeaderName::from_str(&config.real_ip_header)
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+            })?,
+        status_code: StatusCode::from_u16(config.status_code.unwrap_or(403))
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+            })?,
+        message: config.message.map(Bytes::from),
+    };
+    Ok(Box::new(IpRestrictionPlugin {
+        config: Arc::new(config),
+    }))
+}
+
+/// Parses a list of CIDRs, a bare ip address is treated as a single host network
+fn parse_cidrs(cidrs: &[String]) -> PluginResult<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|s| {
+            IpNet::from_str(s)
+                .or_else(|_| IpAddr::from_str(s).map(IpNet::from))
+                .map_err(|e| e.into())
+                .context(SpecificErrSnafu {
+                    name: IP_RESTRICTION_PLUGIN_NAME.to_string(),
+                })
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct IpRestrictionPlugin {
+    config: Arc<IpRestrictionConfig>,
+}
+
+#[derive(Debug)]
+struct IpRestrictionConfig {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    real_ip_header: HeaderName,
+    status_code: StatusCode,
+    message: Option<Bytes>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IpRestrictionConfigRaw {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    /// Proxies whose `real_ip_header` is trusted to carry the client ip
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    #[serde(default = "default_real_ip_header")]
+    real_ip_header: String,
+    status_code: Option<u16>,
+    message: Option<String>,
+}
+
+fn default_real_ip_header() -> String {
+    "x-forwarded-for".to_string()
+}
+
+impl IpRestrictionConfig {
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolves the real client ip.
+    ///
+    /// Only when the peer is a trusted proxy is the `real_ip_header` consulted. The header is
+    /// walked from right to left and the first address that isn't a trusted proxy is the client,
+    /// so that addresses prepended by the client itself can't be used for spoofing.
+    fn resolve_client_ip(&self, session: &Session) -> Option<IpAddr> {
+        let peer = client_ip(session)?;
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+        let header = session
+            .req_header()
+            .headers
+            .get_all(&self.real_ip_header)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Some(self.real_ip(peer, &header))
+    }
+
+    /// Walks the `real_ip_header` of a trusted peer, addresses are canonicalized so that
+    /// IPv4-mapped IPv6 addresses match IPv4 networks
+    fn real_ip(&self, peer: IpAddr, header: &str) -> IpAddr {
+        let mut real_ip = peer;
+        for ip in header
+            .rsplit(',')
+            .filter_map(|s| IpAddr::from_str(s.trim()).ok())
+            .map(|ip| ip.to_canonical())
+        {
+            real_ip = ip;
+            if !self.is_trusted(&ip) {
+                break;
+            }
+        }
+        real_ip
+    }
+
+    fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+#[async_trait]
+impl Plugin for IpRestrictionPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let ip = self.config.resolve_client_ip(session);
+        if !self.config.trusted_proxies.is_empty() {
+            ctx.client_ip = ip;
+        }
+        // requests without an inet peer (e.g. unix socket) are rejected
+        if ip.is_some_and(|ip| self.config.is_allowed(&ip)) {
+            return Ok(false);
+        }
+        send_response(
+            session,
+            self.config.status_code,
+            None,
+            self.config.message.clone(),
+            None,
+        )
+        .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow: &[&str], deny: &[&str], trusted_proxies: &[&str]) -> IpRestrictionConfig {
+        let cidrs = |v: &[&str]| parse_cidrs(&v.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        IpRestrictionConfig {
+            allow: cidrs(allow).unwrap(),
+            deny: cidrs(deny).unwrap(),
+            trusted_proxies: cidrs(trusted_proxies).unwrap(),
+            real_ip_header: HeaderName::from_static("x-forwarded-for"),
+            status_code: StatusCode::FORBIDDEN,
+            message: None,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let cfg = config(&["10.0.0.0/8", "2001:db8::/32"], &["10.1.0.0/16"], &[]);
+        assert!(cfg.is_allowed(&ip("10.2.3.4")));
+        assert!(!cfg.is_allowed(&ip("10.1.3.4")));
+        assert!(!cfg.is_allowed(&ip("192.168.0.1")));
+        assert!(cfg.is_allowed(&ip("2001:db8::1")));
+        let cfg = config(&[], &["192.168.0.1"], &[]);
+        assert!(!cfg.is_allowed(&ip("192.168.0.1")));
+        assert!(cfg.is_allowed(&ip("192.168.0.2")));
+    }
+
+    #[test]
+    fn invalid_cidrs_are_rejected() {
+        assert!(parse_cidrs(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(parse_cidrs(&["nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn forwarded_addresses_are_walked_from_the_right() {
+        let cfg = config(&[], &["1.1.1.1"], &["10.0.0.0/8"]);
+        let peer = ip("10.0.0.1");
+        assert_eq!(cfg.real_ip(peer, "1.2.3.4, 10.0.0.2"), ip("1.2.3.4"));
+        // addresses prepended by the client are ignored
+        assert_eq!(
+            cfg.real_ip(peer, "1.1.1.1, 5.6.7.8, 10.0.0.2"),
+            ip("5.6.7.8")
+        );
+        assert_eq!(cfg.real_ip(peer, "garbage"), peer);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_networks() {
+        let cfg = config(&[], &["1.1.1.1"], &["10.0.0.0/8"]);
+        let real = cfg.real_ip(ip("10.0.0.1"), "::ffff:1.1.1.1");
+        assert_eq!(real, ip("1.1.1.1"));
+        assert!(!cfg.is_allowed(&real));
+        assert_eq!(
+            cfg.real_ip(ip("10.0.0.1"), "2.2.2.2, ::ffff:10.0.0.3"),
+            ip("2.2.2.2")
+        );
+    }
+}
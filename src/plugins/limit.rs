@@ -0,0 +1,259 @@
+//! Pieces shared by the limiting plugins
+
+use std::{collections::HashMap, time::Duration};
+
+use bytes::Bytes;
+use http::{header, HeaderValue, StatusCode};
+use pingora::{http::ResponseHeader, prelude::*};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    core::plugin::PluginCtx,
+    plugins::{errors::*, PluginResult},
+    utils::send_response,
+};
+
+/// Sustained rate with bursts, shared by limit_req and redis_rate
+#[derive(Debug, Deserialize, Validate)]
+pub struct QuotaConf {
+    /// Sustained number of requests per `period`
+    #[validate(range(min = 1))]
+    pub rate: u64,
+    #[serde(default = "default_period", with = "humantime_serde")]
+    #[validate(custom(function = "non_zero_period"))]
+    pub period: Duration,
+    /// Requests allowed at once on top of the sustained rate
+    #[serde(default)]
+    pub burst: u64,
+}
+
+fn default_period() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn non_zero_period(v: &Duration) -> Result<(), ValidationError> {
+    if v.is_zero() {
+        return Err(ValidationError::new("period must not be zero"));
+    }
+    Ok(())
+}
+
+/// Ticks per second of the nanosecond clock of limit_req
+pub const NANOS: u64 = 1_000_000_000;
+
+/// Ticks per second of the microsecond redis clock of redis_rate
+pub const MICROS: u64 = 1_000_000;
+
+/// [`QuotaConf`] in ticks of the clock of the limiter, for GCRA
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    /// Interval between two requests at the sustained rate
+    pub emission: u64,
+    /// How far ahead of the sustained rate a key may go
+    pub tolerance: u64,
+    /// Requests allowed at once
+    pub limit: u64,
+    /// Ticks per second of the clock
+    pub ticks_per_sec: u64,
+}
+
+impl Quota {
+    pub fn new(cfg: &QuotaConf, ticks_per_sec: u64) -> Self {
+        let period = cfg.period.as_nanos() * ticks_per_sec as u128 / NANOS as u128;
+        let emission = (period / cfg.rate as u128).clamp(1, u64::MAX as u128) as u64;
+        Self {
+            emission,
+            tolerance: emission.saturating_mul(cfg.burst),
+            limit: cfg.burst.saturating_add(1),
+            ticks_per_sec,
+        }
+    }
+
+    /// Converts ticks to seconds, rounding up
+    pub fn ceil_secs(&self, ticks: u64) -> u64 {
+        ticks.div_ceil(self.ticks_per_sec)
+    }
+}
+
+/// Whether a request fits in its quota
+pub struct Decision {
+    pub allowed: bool,
+    pub status: RateLimitStatus,
+}
+
+/// Response sent to rejected requests
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RejectionConf {
+    pub status: u16,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl Default for RejectionConf {
+    fn default() -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            body: None,
+            content_type: None,
+        }
+    }
+}
+
+pub struct Rejection {
+    status: StatusCode,
+    body: Option<Bytes>,
+    content_type: Option<String>,
+}
+
+impl Rejection {
+    pub fn new(cfg: RejectionConf, plugin: &str) -> PluginResult<Self> {
+        let status = StatusCode::from_u16(cfg.status)
+            .map_err(|e| e.into())
+            .context(SpecificErrSnafu {
+                name: plugin.to_string(),
+            })?;
+        if let Some(content_type) = &cfg.content_type {
+            HeaderValue::from_str(content_type)
+                .map_err(|e| e.into())
+                .context(SpecificErrSnafu {
+                    name: plugin.to_string(),
+                })?;
+        }
+        Ok(Self {
+            status,
+            body: cfg.body.map(Bytes::from),
+            content_type: cfg.content_type,
+        })
+    }
+
+    /// Sends the rejection, along with the rate-limit headers if `status` is set, or only
+    /// `Retry-After` if `retry_after` is set
+    pub async fn send(
+        &self,
+        session: &mut Session,
+        status: Option<RateLimitStatus>,
+        retry_after: Option<u64>,
+    ) -> Result<()> {
+        let mut headers = HashMap::new();
+        if let Some(status) = status {
+            headers.extend(
+                status
+                    .headers()
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v)),
+            );
+        }
+        if let Some(retry_after) = retry_after {
+            headers.insert(header::RETRY_AFTER.to_string(), retry_after.to_string());
+        }
+        send_response(
+            session,
+            self.status,
+            self.content_type.as_deref(),
+            self.body.clone(),
+            Some(headers),
+        )
+        .await
+    }
+}
+
+/// Quota of the current request, reported in the `X-RateLimit-*` headers
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the quota is fully restored
+    pub reset: u64,
+    /// Seconds until the next request would be allowed, only sent once the quota is used up
+    pub retry_after: u64,
+}
+
+impl RateLimitStatus {
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+            ("x-ratelimit-reset", self.reset.to_string()),
+        ];
+        if self.remaining == 0 {
+            headers.push((header::RETRY_AFTER.as_str(), self.retry_after.to_string()));
+        }
+        headers
+    }
+
+    /// Records the status for the response, the most restrictive one is reported when several
+    /// limiters apply
+    pub fn record(self, ctx: &mut PluginCtx) {
+        let restrictive = ctx
+            .extensions
+            .get::<RateLimitStatus>()
+            .is_none_or(|other| self.remaining < other.remaining);
+        if restrictive {
+            ctx.extensions.insert(self);
+        }
+    }
+
+    /// Sets the headers of the recorded status on the response
+    pub fn apply(ctx: &PluginCtx, resp: &mut ResponseHeader) -> Result<()> {
+        if let Some(status) = ctx.extensions.get::<RateLimitStatus>() {
+            for (name, value) in status.headers() {
+                resp.insert_header(name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(remaining: u64) -> RateLimitStatus {
+        RateLimitStatus {
+            limit: 3,
+            remaining,
+            reset: 2,
+            retry_after: 1,
+        }
+    }
+
+    #[test]
+    fn quotas_are_converted_to_the_ticks_of_the_clock() {
+        let cfg = QuotaConf {
+            rate: 3,
+            period: Duration::from_secs(1),
+            burst: 2,
+        };
+        let nanos = Quota::new(&cfg, NANOS);
+        assert_eq!(nanos.emission, 333_333_333);
+        assert_eq!(nanos.tolerance, 666_666_666);
+        assert_eq!(nanos.limit, 3);
+        assert_eq!(nanos.ceil_secs(NANOS + 1), 2);
+        let micros = Quota::new(&cfg, MICROS);
+        assert_eq!(micros.emission, 333_333);
+        assert_eq!(micros.ceil_secs(MICROS), 1);
+        let huge = QuotaConf {
+            rate: 1,
+            period: Duration::MAX,
+            burst: u64::MAX,
+        };
+        assert_eq!(Quota::new(&huge, NANOS).emission, u64::MAX);
+        assert_eq!(Quota::new(&huge, NANOS).limit, u64::MAX);
+    }
+
+    #[test]
+    fn retry_after_is_only_sent_once_the_quota_is_used_up() {
+        let names = |status: RateLimitStatus| {
+            status
+                .headers()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        assert!(!names(status(1)).contains(&"retry-after"));
+        assert!(names(status(0)).contains(&"retry-after"));
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use lru::LruCache;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{
+        errors::*,
+        limit::{Rejection, RejectionConf},
+        PluginResult,
+    },
+    utils::template::Template,
+};
+
+pub const LIMIT_BANDWIDTH_PLUGIN_NAME: &str = "limit_bandwidth";
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "rate_or_quota"))]
+pub struct LimitBandwidthConf {
+    /// Sustained throughput of the response bodies in bytes per second
+    #[validate(range(min = 1))]
+    pub rate: Option<u64>,
+    /// Bytes sent at once on top of the sustained rate
+    #[serde(default)]
+    pub burst: u64,
+    /// Template of the key whose requests share the throughput, each request is throttled on
+    /// its own if not set
+    pub key: Option<String>,
+    /// Caps the response bytes sent per window, responses already streaming when the quota runs
+    /// out are sent in full
+    #[validate(nested)]
+    pub quota: Option<ByteQuotaConf>,
+    /// Maximum number of tracked keys, the least recently seen ones are evicted first
+    #[serde(default = "default_max_keys")]
+    #[validate(range(min = 1))]
+    pub max_keys: usize,
+    /// Response sent to the requests of keys which used up their quota
+    #[serde(default)]
+    pub rejection: RejectionConf,
+    /// Only logs the requests that would be rejected, throttling still applies
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ByteQuotaConf {
+    #[validate(range(min = 1))]
+    pub bytes: u64,
+    #[serde(with = "humantime_serde")]
+    #[validate(custom(function = "non_zero_window"))]
+    pub window: Duration,
+    /// Template of the key the quota applies to, `${identity}` by default. Requests whose key
+    /// renders empty aren't capped
+    pub key: Option<String>,
+}
+
+fn default_max_keys() -> usize {
+    10_000
+}
+
+fn non_zero_window(v: &Duration) -> Result<(), ValidationError> {
+    if v.is_zero() {
+        return Err(ValidationError::new("window must not be zero"));
+    }
+    Ok(())
+}
+
+fn rate_or_quota(cfg: &LimitBandwidthConf) -> Result<(), ValidationError> {
+    if cfg.rate.is_none() && cfg.quota.is_none() {
+        return Err(ValidationError::new("either rate or quota must be set"));
+    }
+    Ok(())
+}
+
+pub fn create_limit_bandwidth_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: LIMIT_BANDWIDTH_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: LimitBandwidthConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: LIMIT_BANDWIDTH_PLUGIN_NAME.to_string(),
+    })?;
+    cfg.validate().context(ValidateErrSnafu {
+        name: LIMIT_BANDWIDTH_PLUGIN_NAME.to_string(),
+    })?;
+    let parse = |template: &str| {
+        Template::parse(template).context(TemplateSnafu {
+            name: LIMIT_BANDWIDTH_PLUGIN_NAME.to_string(),
+        })
+    };
+    let max_keys = NonZeroUsize::new(cfg.max_keys).unwrap_or(NonZeroUsize::MIN);
+    let throttle = match cfg.rate {
+        Some(rate) => Some(Throttle {
+            ns_per_byte: 1e9 / rate as f64,
+            tolerance: (cfg.burst as f64 * 1e9 / rate as f64) as u64,
+            key: cfg.key.as_deref().map(parse).transpose()?,
+            tats: Mutex::new(LruCache::new(max_keys)),
+        }),
+        None => None,
+    };
+    let quota = match cfg.quota {
+        Some(quota) => Some(ByteQuota {
+            bytes: quota.bytes,
+            window: quota.window,
+            key: parse(quota.key.as_deref().unwrap_or("${identity}"))?,
+            usages: Mutex::new(LruCache::new(max_keys)),
+        }),
+        None => None,
+    };
+    Ok(Box::new(LimitBandwidthPlugin {
+        inner: Arc::new(LimitBandwidth {
+            origin: Instant::now(),
+            throttle,
+            quota,
+            rejection: Rejection::new(cfg.rejection, LIMIT_BANDWIDTH_PLUGIN_NAME)?,
+            dry_run: cfg.dry_run,
+        }),
+    }))
+}
+
+/// Spreads the response bytes over time, the same way GCRA spreads requests
+struct Throttle {
+    ns_per_byte: f64,
+    /// How far ahead of the sustained rate a key may go, in ns
+    tolerance: u64,
+    key: Option<Template>,
+    /// Theoretical arrival times of the next byte in ns since `origin`, indexed by key
+    tats: Mutex<LruCache<String, u64>>,
+}
+
+impl Throttle {
+    /// Accounts `bytes` sent at `now` and returns how long to wait before sending more
+    fn delay(&self, tat: &mut u64, now: u64, bytes: usize) -> Option<Duration> {
+        *tat = (*tat).max(now) + (bytes as f64 * self.ns_per_byte) as u64;
+        let ahead = *tat - now;
+        (ahead > self.tolerance).then(|| Duration::from_nanos(ahead - self.tolerance))
+    }
+}
+
+/// Soft cap of the bytes per window, checked when requests arrive while the bytes are counted as
+/// they are sent, so the responses in flight may overrun it
+struct ByteQuota {
+    bytes: u64,
+    window: Duration,
+    key: Template,
+    usages: Mutex<LruCache<String, Usage>>,
+}
+
+/// Bytes sent by a key in its current window
+struct Usage {
+    started_at: Instant,
+    bytes: u64,
+}
+
+impl ByteQuota {
+    /// Returns the seconds until the window of the key ends if it used up its quota at `now`
+    fn exhausted(&self, key: &str, now: Instant) -> Option<u64> {
+        let mut usages = self.usages.lock().unwrap();
+        let usage = usages.get(key)?;
+        let elapsed = now.saturating_duration_since(usage.started_at);
+        if elapsed >= self.window || usage.bytes < self.bytes {
+            return None;
+        }
+        Some((self.window - elapsed).as_millis().div_ceil(1000) as u64)
+    }
+
+    /// Accounts `bytes` sent at `now`, starting a new window if the last one ended
+    fn consume(&self, key: &str, bytes: usize, now: Instant) {
+        let mut usages = self.usages.lock().unwrap();
+        let usage = usages.get_or_insert_mut(key.to_string(), || Usage {
+            started_at: now,
+            bytes: 0,
+        });
+        if now.saturating_duration_since(usage.started_at) >= self.window {
+            usage.started_at = now;
+            usage.bytes = 0;
+        }
+        usage.bytes += bytes as u64;
+    }
+}
+
+/// State of the current request, per limit_bandwidth plugin it went through
+#[derive(Clone, Default)]
+struct Transfers(HashMap<usize, Transfer>);
+
+#[derive(Clone)]
+struct Transfer {
+    /// Key sharing the throughput, the request has its own theoretical arrival time if not set
+    throttle_key: Option<String>,
+    tat: u64,
+    quota_key: Option<String>,
+}
+
+struct LimitBandwidth {
+    origin: Instant,
+    throttle: Option<Throttle>,
+    quota: Option<ByteQuota>,
+    rejection: Rejection,
+    dry_run: bool,
+}
+
+/// Throttles the response bodies per request or per key, and caps the bytes sent per window
+pub struct LimitBandwidthPlugin {
+    inner: Arc<LimitBandwidth>,
+}
+
+impl LimitBandwidthPlugin {
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+}
+
+#[async_trait]
+impl Plugin for LimitBandwidthPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let inner = &self.inner;
+        let quota_key = inner
+            .quota
+            .as_ref()
+            .map(|quota| quota.key.render(session, ctx))
+            .filter(|key| !key.is_empty());
+        if let Some((quota, key)) = inner.quota.as_ref().zip(quota_key.as_ref()) {
+            if let Some(retry_after) = quota.exhausted(key, Instant::now()) {
+                if !inner.dry_run {
+                    inner
+                        .rejection
+                        .send(session, None, Some(retry_after))
+                        .await?;
+                    return Ok(true);
+                }
+                warn!("limit_bandwidth would reject request, key: {}", key);
+            }
+        }
+        let throttle_key = inner
+            .throttle
+            .as_ref()
+            .and_then(|throttle| throttle.key.as_ref())
+            .map(|key| key.render(session, ctx));
+        if ctx.extensions.get::<Transfers>().is_none() {
+            ctx.extensions.insert(Transfers::default());
+        }
+        if let Some(transfers) = ctx.extensions.get_mut::<Transfers>() {
+            transfers.0.insert(
+                self.id(),
+                Transfer {
+                    throttle_key,
+                    tat: 0,
+                    quota_key,
+                },
+            );
+        }
+        Ok(false)
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut PluginCtx,
+    ) -> Result<Option<Duration>> {
+        let inner = &self.inner;
+        let len = body.as_ref().map_or(0, |b| b.len());
+        if len == 0 {
+            return Ok(None);
+        }
+        let Some(transfer) = ctx
+            .extensions
+            .get_mut::<Transfers>()
+            .and_then(|transfers| transfers.0.get_mut(&self.id()))
+        else {
+            return Ok(None);
+        };
+        if let Some((quota, key)) = inner.quota.as_ref().zip(transfer.quota_key.as_ref()) {
+            quota.consume(key, len, Instant::now());
+        }
+        let Some(throttle) = &inner.throttle else {
+            return Ok(None);
+        };
+        let now = inner.origin.elapsed().as_nanos() as u64;
+        match &transfer.throttle_key {
+            Some(key) => {
+                let mut tats = throttle.tats.lock().unwrap();
+                let tat = tats.get_or_insert_mut(key.clone(), || now);
+                Ok(throttle.delay(tat, now, len))
+            }
+            None => Ok(throttle.delay(&mut transfer.tat, now, len)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    /// 1000 bytes per second with bursts of 500 bytes
+    fn throttle() -> Throttle {
+        Throttle {
+            ns_per_byte: 1e6,
+            tolerance: 500 * MS,
+            key: None,
+            tats: Mutex::new(LruCache::new(NonZeroUsize::MIN)),
+        }
+    }
+
+    #[test]
+    fn bodies_are_delayed_once_past_the_burst() {
+        let throttle = throttle();
+        let mut tat = 0;
+        assert_eq!(throttle.delay(&mut tat, 1000 * MS, 500), None);
+        assert_eq!(
+            throttle.delay(&mut tat, 1000 * MS, 500),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            throttle.delay(&mut tat, 1500 * MS, 100),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(tat, 2100 * MS);
+        // idle time doesn't add up beyond the burst
+        assert_eq!(throttle.delay(&mut tat, 10_000 * MS, 500), None);
+        assert_eq!(
+            throttle.delay(&mut tat, 10_000 * MS, 1),
+            Some(Duration::from_millis(1))
+        );
+    }
+
+    fn quota() -> ByteQuota {
+        ByteQuota {
+            bytes: 100,
+            window: Duration::from_secs(10),
+            key: Template::parse("${identity}").unwrap(),
+            usages: Mutex::new(LruCache::new(NonZeroUsize::new(2).unwrap())),
+        }
+    }
+
+    #[test]
+    fn quota_is_used_up_until_the_window_ends() {
+        let quota = quota();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        assert_eq!(quota.exhausted("a", start), None);
+        quota.consume("a", 60, start);
+        assert_eq!(quota.exhausted("a", at(1000)), None);
+        // the responses in flight overrun the quota
+        quota.consume("a", 60, at(1000));
+        quota.consume("a", 60, at(1500));
+        assert_eq!(quota.exhausted("a", at(1500)), Some(9));
+        assert_eq!(quota.exhausted("a", at(9001)), Some(1));
+        assert_eq!(quota.exhausted("b", at(1500)), None);
+        assert_eq!(quota.exhausted("a", at(10_000)), None);
+        // a new window starts with the next bytes
+        quota.consume("a", 60, at(10_000));
+        assert_eq!(quota.exhausted("a", at(10_000)), None);
+        quota.consume("a", 40, at(11_000));
+        assert_eq!(quota.exhausted("a", at(11_000)), Some(9));
+    }
+}
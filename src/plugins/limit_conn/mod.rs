@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use http::StatusCode;
+use log::warn;
+use pingora::prelude::*;
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use tokio::{sync::Notify, time::Instant};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{
+        errors::*,
+        limit::{Rejection, RejectionConf},
+        PluginResult,
+    },
+    utils::template::Template,
+};
+
+pub const LIMIT_CONN_PLUGIN_NAME: &str = "limit_conn";
+
+#[derive(Debug, Deserialize, Validate)]
+#[validate(schema(function = "adaptive_within_conn"))]
+pub struct LimitConnConf {
+    /// Maximum number of requests in flight per key
+    #[validate(range(min = 1))]
+    pub conn: usize,
+    /// Template of the key requests are limited by, `${client_ip}` by default
+    pub key: Option<String>,
+    /// Limits of specific keys, overriding the default one
+    #[serde(default)]
+    pub overrides: HashMap<String, usize>,
+    /// Maximum number of requests per key waiting for a slot, requests are rejected right away
+    /// when it's 0
+    #[serde(default)]
+    pub queue: usize,
+    /// How long a queued request waits for a slot before it's rejected
+    #[serde(default = "default_queue_timeout", with = "humantime_serde")]
+    pub queue_timeout: Duration,
+    /// Derives the limit of the keys without override from the observed latency, `conn` being
+    /// its upper bound
+    #[validate(nested)]
+    pub adaptive: Option<AdaptiveConf>,
+    #[serde(default = "default_rejection")]
+    pub rejection: RejectionConf,
+    /// Only logs the requests that would be rejected
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AdaptiveConf {
+    /// Lower bound of the limit
+    #[serde(default = "default_min_conn")]
+    #[validate(range(min = 1))]
+    pub min_conn: usize,
+    /// How much the recent latency may exceed the long term one before the limit shrinks,
+    /// e.g. 2.0 tolerates twice the usual latency
+    #[serde(default = "default_tolerance")]
+    #[validate(range(min = 1.0))]
+    pub tolerance: f64,
+    /// Weight of each new limit estimate, lower values change the limit more slowly
+    #[serde(default = "default_smoothing")]
+    #[validate(range(exclusive_min = 0.0, max = 1.0))]
+    pub smoothing: f64,
+}
+
+fn default_queue_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_rejection() -> RejectionConf {
+    RejectionConf {
+        status: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+        ..Default::default()
+    }
+}
+
+fn default_min_conn() -> usize {
+    1
+}
+
+fn default_tolerance() -> f64 {
+    2.0
+}
+
+fn default_smoothing() -> f64 {
+    0.2
+}
+
+fn adaptive_within_conn(cfg: &LimitConnConf) -> Result<(), ValidationError> {
+    if cfg.adaptive.as_ref().is_some_and(|a| a.min_conn > cfg.conn) {
+        return Err(ValidationError::new(
+            "adaptive.min_conn must not exceed conn",
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_limit_conn_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: LIMIT_CONN_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: LimitConnConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: LIMIT_CONN_PLUGIN_NAME.to_string(),
+    })?;
+    cfg.validate().context(ValidateErrSnafu {
+        name: LIMIT_CONN_PLUGIN_NAME.to_string(),
+    })?;
+    let key =
+        Template::parse(cfg.key.as_deref().unwrap_or("${client_ip}")).context(TemplateSnafu {
+            name: LIMIT_CONN_PLUGIN_NAME.to_string(),
+        })?;
+    Ok(Box::new(LimitConnPlugin {
+        limiter: Arc::new(Limiter {
+            keys: Mutex::new(HashMap::new()),
+            gradient: cfg.adaptive.map(|a| Gradient::new(&a, cfg.conn)),
+        }),
+        key,
+        conn: cfg.conn,
+        overrides: Arc::new(cfg.overrides),
+        queue: cfg.queue,
+        queue_timeout: cfg.queue_timeout,
+        rejection: Arc::new(Rejection::new(cfg.rejection, LIMIT_CONN_PLUGIN_NAME)?),
+        dry_run: cfg.dry_run,
+    }))
+}
+
+/// Number of samples the long term latency averages over
+const LONG_WINDOW: f64 = 600.0;
+/// Number of samples the recent latency averages over
+const SHORT_WINDOW: f64 = 10.0;
+
+/// Gradient concurrency limit, shrinks the limit of a key when its recent latency rises above
+/// its long term one and grows it by about its square root otherwise
+struct Gradient {
+    min: f64,
+    max: f64,
+    tolerance: f64,
+    smoothing: f64,
+}
+
+/// Adaptive limit of a key
+struct GradientState {
+    limit: f64,
+    /// Latencies in seconds
+    long_rtt: f64,
+    short_rtt: f64,
+}
+
+impl GradientState {
+    fn limit(&self) -> usize {
+        self.limit as usize
+    }
+}
+
+impl Gradient {
+    fn new(cfg: &AdaptiveConf, max: usize) -> Self {
+        Self {
+            min: cfg.min_conn as f64,
+            max: max as f64,
+            tolerance: cfg.tolerance,
+            smoothing: cfg.smoothing,
+        }
+    }
+
+    /// Updates the limit of a key with the latency of one of its requests, `in_flight` being the
+    /// number of requests of the key when it finished
+    fn sample(&self, state: &mut Option<GradientState>, rtt: Duration, in_flight: usize) {
+        let rtt = rtt.as_secs_f64();
+        let s = state.get_or_insert_with(|| GradientState {
+            limit: self.max,
+            long_rtt: rtt,
+            short_rtt: rtt,
+        });
+        s.long_rtt += (rtt - s.long_rtt) / LONG_WINDOW;
+        s.short_rtt += (rtt - s.short_rtt) / SHORT_WINDOW;
+        // Latency dropped for good, let the long term average catch up
+        if s.long_rtt > s.short_rtt * 2.0 {
+            s.long_rtt *= 0.95;
+        }
+        // Too few requests to tell whether the limit is too high
+        if (in_flight as f64) < s.limit / 2.0 || s.short_rtt <= 0.0 {
+            return;
+        }
+        let gradient = (self.tolerance * s.long_rtt / s.short_rtt).clamp(0.5, 1.0);
+        let estimate = s.limit * gradient + s.limit.sqrt();
+        s.limit = (s.limit * (1.0 - self.smoothing) + estimate * self.smoothing)
+            .clamp(self.min, self.max);
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    in_flight: usize,
+    waiting: usize,
+    /// Wakes up a waiting request whenever a slot is released, requests arriving meanwhile may
+    /// take the slot first so waiting ones are not served strictly in arrival order
+    notify: Arc<Notify>,
+    /// Adaptive limit of the key, starts over at the maximum once the key has no requests
+    gradient: Option<GradientState>,
+}
+
+/// Counts the requests in flight per key, keys without requests are removed
+struct Limiter {
+    keys: Mutex<HashMap<String, Slots>>,
+    gradient: Option<Gradient>,
+}
+
+impl Limiter {
+    /// Takes a slot of the key right away if one is free, `limit` gives the limit of the key
+    fn try_acquire(self: &Arc<Self>, key: &str, limit: impl Fn(&Slots) -> usize) -> Option<Permit> {
+        let mut keys = self.keys.lock().unwrap();
+        let slots = keys.entry(key.to_string()).or_default();
+        if slots.in_flight >= limit(slots) {
+            return None;
+        }
+        slots.in_flight += 1;
+        Some(self.permit(key))
+    }
+
+    /// Takes a slot of the key, waiting up to `timeout` for one among at most `queue` requests
+    async fn acquire(
+        self: &Arc<Self>,
+        key: &str,
+        limit: impl Fn(&Slots) -> usize,
+        queue: usize,
+        timeout: Duration,
+    ) -> Option<Permit> {
+        let deadline = Instant::now() + timeout;
+        // Leaves the queue once the lock is released
+        let mut queued: Option<Queued> = None;
+        loop {
+            let notify;
+            let mut notified;
+            {
+                let mut keys = self.keys.lock().unwrap();
+                let slots = keys.entry(key.to_string()).or_default();
+                if slots.in_flight < limit(slots) {
+                    slots.in_flight += 1;
+                    return Some(self.permit(key));
+                }
+                if queued.is_none() {
+                    if slots.waiting >= queue {
+                        return None;
+                    }
+                    slots.waiting += 1;
+                    queued = Some(Queued { limiter: self, key });
+                }
+                // Registered before unlocking so that no release in between is missed
+                notify = slots.notify.clone();
+                notified = Box::pin(notify.notified());
+                notified.as_mut().enable();
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    fn permit(self: &Arc<Self>, key: &str) -> Permit {
+        Permit {
+            limiter: self.clone(),
+            key: key.to_string(),
+            acquired_at: Instant::now(),
+        }
+    }
+
+    fn release(&self, key: &str, rtt: Duration) {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(slots) = keys.get_mut(key) else {
+            return;
+        };
+        if let Some(gradient) = &self.gradient {
+            gradient.sample(&mut slots.gradient, rtt, slots.in_flight);
+        }
+        slots.in_flight -= 1;
+        if slots.waiting > 0 {
+            slots.notify.notify_one();
+        } else if slots.in_flight == 0 {
+            keys.remove(key);
+        }
+    }
+}
+
+/// A request waiting for a slot, leaves the queue when dropped, e.g. on timeout or when the
+/// client goes away
+struct Queued<'a> {
+    limiter: &'a Limiter,
+    key: &'a str,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.limiter.keys.lock().unwrap();
+        if let Some(slots) = keys.get_mut(self.key) {
+            slots.waiting -= 1;
+            if slots.waiting == 0 && slots.in_flight == 0 {
+                keys.remove(self.key);
+            }
+        }
+    }
+}
+
+/// A slot taken by a request, released when dropped
+struct Permit {
+    limiter: Arc<Limiter>,
+    key: String,
+    acquired_at: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.key, self.acquired_at.elapsed());
+    }
+}
+
+/// Slots held by the current request, one per limit_conn plugin it went through
+#[derive(Clone, Default)]
+struct Permits(Vec<Arc<Permit>>);
+
+/// Limits the number of requests in flight per key, from the request filter until the request
+/// is logged
+pub struct LimitConnPlugin {
+    limiter: Arc<Limiter>,
+    key: Template,
+    conn: usize,
+    overrides: Arc<HashMap<String, usize>>,
+    queue: usize,
+    queue_timeout: Duration,
+    rejection: Arc<Rejection>,
+    dry_run: bool,
+}
+
+#[async_trait]
+impl Plugin for LimitConnPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let key = self.key.render(session, ctx);
+        let override_limit = self.overrides.get(&key).copied();
+        let limit = |slots: &Slots| {
+            override_limit
+                .or(slots.gradient.as_ref().map(GradientState::limit))
+                .unwrap_or(self.conn)
+        };
+        let permit = if self.dry_run {
+            self.limiter.try_acquire(&key, limit)
+        } else {
+            self.limiter
+                .acquire(&key, limit, self.queue, self.queue_timeout)
+                .await
+        };
+        let Some(permit) = permit else {
+            if !self.dry_run {
+                self.rejection.send(session, None, Some(1)).await?;
+                return Ok(true);
+            }
+            warn!("limit_conn would reject request, key: {}", key);
+            return Ok(false);
+        };
+        if ctx.extensions.get::<Permits>().is_none() {
+            ctx.extensions.insert(Permits::default());
+        }
+        if let Some(permits) = ctx.extensions.get_mut::<Permits>() {
+            permits.0.push(Arc::new(permit));
+        }
+        Ok(false)
+    }
+
+    async fn logging(&self, _session: &mut Session, _e: Option<&Error>, ctx: &mut PluginCtx) {
+        if let Some(permits) = ctx.extensions.get_mut::<Permits>() {
+            permits
+                .0
+                .retain(|permit| !Arc::ptr_eq(&permit.limiter, &self.limiter));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_session;
+
+    fn limiter(adaptive: Option<&str>) -> Arc<Limiter> {
+        Arc::new(Limiter {
+            keys: Mutex::new(HashMap::new()),
+            gradient: adaptive.map(|cfg| Gradient::new(&serde_yaml::from_str(cfg).unwrap(), 10)),
+        })
+    }
+
+    fn one(_: &Slots) -> usize {
+        1
+    }
+
+    /// Returns the requests in flight and waiting of the key, if it's tracked
+    fn slots(limiter: &Limiter, key: &str) -> Option<(usize, usize)> {
+        let keys = limiter.keys.lock().unwrap();
+        keys.get(key).map(|slots| (slots.in_flight, slots.waiting))
+    }
+
+    #[tokio::test]
+    async fn queued_requests_take_the_released_slots() {
+        let limiter = limiter(None);
+        let first = limiter.try_acquire("k", one).unwrap();
+        assert!(limiter.try_acquire("k", one).is_none());
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire("k", one, 1, Duration::from_secs(5))
+                    .await
+                    .is_some()
+            }
+        });
+        while slots(&limiter, "k") != Some((1, 1)) {
+            tokio::task::yield_now().await;
+        }
+        // the queue is full
+        let rejected = limiter.acquire("k", one, 1, Duration::from_secs(5)).await;
+        assert!(rejected.is_none());
+        drop(first);
+        assert!(queued.await.unwrap());
+        assert_eq!(slots(&limiter, "k"), None);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_time_out() {
+        let limiter = limiter(None);
+        let _first = limiter.try_acquire("k", one).unwrap();
+        let queued = limiter
+            .acquire("k", one, 1, Duration::from_millis(20))
+            .await;
+        assert!(queued.is_none());
+        assert_eq!(slots(&limiter, "k"), Some((1, 0)));
+        let unqueued = limiter.acquire("k", one, 0, Duration::from_secs(5)).await;
+        assert!(unqueued.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_requests_leave_the_queue() {
+        let limiter = limiter(None);
+        let first = limiter.try_acquire("k", one).unwrap();
+        let queued = limiter.acquire("k", one, 1, Duration::from_secs(5));
+        let mut queued = Box::pin(queued);
+        let waited = tokio::time::timeout(Duration::from_millis(10), queued.as_mut()).await;
+        assert!(waited.is_err());
+        assert_eq!(slots(&limiter, "k"), Some((1, 1)));
+        // the client went away
+        drop(queued);
+        assert_eq!(slots(&limiter, "k"), Some((1, 0)));
+        drop(first);
+        assert_eq!(slots(&limiter, "k"), None);
+    }
+
+    #[tokio::test]
+    async fn slots_are_released_when_the_request_is_logged() {
+        let plugin = |limiter: &Arc<Limiter>| LimitConnPlugin {
+            limiter: limiter.clone(),
+            key: Template::parse("k").unwrap(),
+            conn: 1,
+            overrides: Arc::new(HashMap::new()),
+            queue: 0,
+            queue_timeout: Duration::from_secs(1),
+            rejection: Arc::new(
+                Rejection::new(default_rejection(), LIMIT_CONN_PLUGIN_NAME).unwrap(),
+            ),
+            dry_run: false,
+        };
+        let (first, second) = (limiter(None), limiter(None));
+        let (a, b) = (plugin(&first), plugin(&second));
+        let mut session = test_session("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await;
+        let mut ctx = PluginCtx::default();
+        assert!(!a.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert!(!b.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert_eq!(slots(&first, "k"), Some((1, 0)));
+        a.logging(&mut session, None, &mut ctx).await;
+        assert_eq!(slots(&first, "k"), None);
+        // the slots of other limit_conn plugins are kept until they log the request
+        assert_eq!(slots(&second, "k"), Some((1, 0)));
+        b.logging(&mut session, None, &mut ctx).await;
+        assert_eq!(slots(&second, "k"), None);
+    }
+
+    /// Finishes `n` requests of the key with the latency `rtt`, `n` being in flight at once
+    fn finish(limiter: &Limiter, key: &str, n: usize, rtt: Duration) {
+        limiter
+            .keys
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .in_flight += n;
+        for _ in 0..n {
+            limiter.release(key, rtt);
+        }
+    }
+
+    fn adaptive_limit(limiter: &Limiter, key: &str) -> Option<usize> {
+        let keys = limiter.keys.lock().unwrap();
+        keys.get(key)?.gradient.as_ref().map(GradientState::limit)
+    }
+
+    #[test]
+    fn adaptive_limits_follow_the_latency_of_each_key() {
+        let limiter = limiter(Some("{min_conn: 5, tolerance: 1.0, smoothing: 0.5}"));
+        // keeps the key tracked between the batches
+        let _idle = limiter.try_acquire("slow", |_| usize::MAX).unwrap();
+        let _idle2 = limiter.try_acquire("fast", |_| usize::MAX).unwrap();
+        for _ in 0..20 {
+            finish(&limiter, "slow", 10, Duration::from_millis(10));
+            finish(&limiter, "fast", 10, Duration::from_millis(10));
+        }
+        assert_eq!(adaptive_limit(&limiter, "slow"), Some(10));
+        for _ in 0..20 {
+            finish(&limiter, "slow", 10, Duration::from_millis(100));
+            finish(&limiter, "fast", 10, Duration::from_millis(10));
+        }
+        assert_eq!(adaptive_limit(&limiter, "slow"), Some(5));
+        assert_eq!(adaptive_limit(&limiter, "fast"), Some(10));
+        for _ in 0..20 {
+            finish(&limiter, "slow", 10, Duration::from_millis(10));
+        }
+        assert_eq!(adaptive_limit(&limiter, "slow"), Some(10));
+    }
+
+    #[test]
+    fn adaptive_limits_only_change_under_load() {
+        let limiter = limiter(Some("{}"));
+        let _idle = limiter.try_acquire("k", |_| usize::MAX).unwrap();
+        for _ in 0..20 {
+            finish(&limiter, "k", 1, Duration::from_millis(10));
+        }
+        for _ in 0..20 {
+            finish(&limiter, "k", 1, Duration::from_secs(1));
+        }
+        assert_eq!(adaptive_limit(&limiter, "k"), Some(10));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use log::warn;
+use lru::LruCache;
+use pingora::{http::ResponseHeader, prelude::*};
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use validator::Validate;
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{
+        errors::*,
+        limit::{Decision, Quota, QuotaConf, RateLimitStatus, Rejection, RejectionConf, NANOS},
+        PluginResult,
+    },
+    utils::template::Template,
+};
+
+pub const LIMIT_REQ_PLUGIN_NAME: &str = "limit_req";
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct LimitReqConf {
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub quota: QuotaConf,
+    /// Template of the key requests are limited by, `${client_ip}` by default
+    pub key: Option<String>,
+    /// Quotas of specific keys, overriding the default one
+    #[serde(default)]
+    #[validate(nested)]
+    pub overrides: HashMap<String, QuotaConf>,
+    /// Maximum number of tracked keys, the least recently seen ones are evicted first
+    #[serde(default = "default_max_keys")]
+    #[validate(range(min = 1))]
+    pub max_keys: usize,
+    /// Whether to send the `X-RateLimit-*` and `Retry-After` headers
+    #[serde(default = "default_headers")]
+    pub headers: bool,
+    #[serde(default)]
+    pub rejection: RejectionConf,
+    /// Only logs the requests that would be rejected
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_max_keys() -> usize {
+    100_000
+}
+
+fn default_headers() -> bool {
+    true
+}
+
+pub fn create_limit_req_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: LIMIT_REQ_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: LimitReqConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: LIMIT_REQ_PLUGIN_NAME.to_string(),
+    })?;
+    cfg.validate().context(ValidateErrSnafu {
+        name: LIMIT_REQ_PLUGIN_NAME.to_string(),
+    })?;
+    let key =
+        Template::parse(cfg.key.as_deref().unwrap_or("${client_ip}")).context(TemplateSnafu {
+            name: LIMIT_REQ_PLUGIN_NAME.to_string(),
+        })?;
+    let max_keys = NonZeroUsize::new(cfg.max_keys).unwrap_or(NonZeroUsize::MIN);
+    Ok(Box::new(LimitReqPlugin {
+        limiter: Arc::new(Gcra {
+            origin: Instant::now(),
+            states: Mutex::new(LruCache::new(max_keys)),
+        }),
+        key,
+        quota: Quota::new(&cfg.quota, NANOS),
+        overrides: Arc::new(
+            cfg.overrides
+                .iter()
+                .map(|(key, quota)| (key.clone(), Quota::new(quota, NANOS)))
+                .collect(),
+        ),
+        headers: cfg.headers,
+        rejection: Arc::new(Rejection::new(cfg.rejection, LIMIT_REQ_PLUGIN_NAME)?),
+        dry_run: cfg.dry_run,
+    }))
+}
+
+/// Generic cell rate algorithm, a token bucket which only stores the theoretical arrival time
+/// of the next request of each key
+///
+/// A key whose theoretical arrival time has passed is at full capacity, which is why evicting
+/// idle keys loses nothing.
+struct Gcra {
+    origin: Instant,
+    /// Theoretical arrival times in ns since `origin`, indexed by key
+    states: Mutex<LruCache<String, u64>>,
+}
+
+impl Gcra {
+    /// Takes a request off the quota of the key, rejected requests don't count
+    fn acquire(&self, key: String, quota: &Quota) -> Decision {
+        let now = self.origin.elapsed().as_nanos() as u64;
+        self.acquire_at(key, quota, now)
+    }
+
+    /// Same as [`Gcra::acquire`], `now` being in ns since `origin`
+    fn acquire_at(&self, key: String, quota: &Quota, now: u64) -> Decision {
+        // a huge burst makes the tolerance saturate, which must not overflow the sums below
+        let window = quota.tolerance.saturating_add(quota.emission);
+        let mut states = self.states.lock().unwrap();
+        let tat = states.get(&key).copied().unwrap_or(now).max(now);
+        let new_tat = tat.saturating_add(quota.emission);
+        let allow_at = new_tat.saturating_sub(window);
+        if now < allow_at {
+            return Decision {
+                allowed: false,
+                status: RateLimitStatus {
+                    limit: quota.limit,
+                    remaining: 0,
+                    reset: quota.ceil_secs(tat - now),
+                    retry_after: quota.ceil_secs(allow_at - now),
+                },
+            };
+        }
+        states.put(key, new_tat);
+        Decision {
+            allowed: true,
+            status: RateLimitStatus {
+                limit: quota.limit,
+                remaining: now.saturating_add(window).saturating_sub(new_tat) / quota.emission,
+                reset: quota.ceil_secs(new_tat - now),
+                retry_after: quota
+                    .ceil_secs(new_tat.saturating_sub(now.saturating_add(quota.tolerance))),
+            },
+        }
+    }
+}
+
+/// Limits requests per key to a sustained rate with bursts
+pub struct LimitReqPlugin {
+    limiter: Arc<Gcra>,
+    key: Template,
+    quota: Quota,
+    overrides: Arc<HashMap<String, Quota>>,
+    headers: bool,
+    rejection: Arc<Rejection>,
+    dry_run: bool,
+}
+
+#[async_trait]
+impl Plugin for LimitReqPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let key = self.key.render(session, ctx);
+        let quota = self.overrides.get(&key).unwrap_or(&self.quota);
+        let decision = self.limiter.acquire(key.clone(), quota);
+        if !decision.allowed {
+            if !self.dry_run {
+                let status = self.headers.then_some(decision.status);
+                self.rejection.send(session, status, None).await?;
+                return Ok(true);
+            }
+            warn!("limit_req would reject request, key: {}", key);
+        }
+        if self.headers {
+            decision.status.record(ctx);
+        }
+        Ok(false)
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if !self.headers {
+            return Ok(());
+        }
+        RateLimitStatus::apply(ctx, upstream_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn gcra() -> Gcra {
+        Gcra {
+            origin: Instant::now(),
+            states: Mutex::new(LruCache::new(NonZeroUsize::MIN)),
+        }
+    }
+
+    fn quota(rate: u64, burst: u64) -> Quota {
+        Quota::new(
+            &QuotaConf {
+                rate,
+                burst,
+                period: Duration::from_secs(1),
+            },
+            NANOS,
+        )
+    }
+
+    #[test]
+    fn burst_is_allowed_at_once_then_the_rate() {
+        let gcra = gcra();
+        let quota = quota(1, 2);
+        let remaining: Vec<_> = (0..3)
+            .map(|_| gcra.acquire_at("k".to_string(), &quota, 10 * SEC))
+            .map(|d| (d.allowed, d.status.remaining))
+            .collect();
+        assert_eq!(remaining, [(true, 2), (true, 1), (true, 0)]);
+        let rejected = gcra.acquire_at("k".to_string(), &quota, 10 * SEC);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.status.retry_after, 1);
+        assert_eq!(rejected.status.reset, 3);
+        assert!(gcra.acquire_at("k".to_string(), &quota, 11 * SEC).allowed);
+        assert!(!gcra.acquire_at("k".to_string(), &quota, 11 * SEC).allowed);
+    }
+
+    #[test]
+    fn huge_bursts_do_not_overflow() {
+        let gcra = gcra();
+        let quota = quota(1, u64::MAX);
+        for _ in 0..3 {
+            let decision = gcra.acquire_at("k".to_string(), &quota, u64::MAX - SEC);
+            assert!(decision.allowed);
+        }
+    }
+}
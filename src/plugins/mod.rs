@@ -5,8 +5,19 @@ use once_cell::sync::Lazy;
 use serde_yaml::Value as YamlValue;
 
 pub mod cms_rate;
+pub mod cors;
+pub mod direct_response;
 pub mod echo;
 pub mod errors;
+pub mod fault_injection;
+pub mod headers;
+pub mod ip_restriction;
+pub mod limit;
+pub mod limit_bandwidth;
+pub mod limit_conn;
+pub mod limit_req;
+pub mod redis_rate;
+pub mod request_id;
 
 use errors::*;
 
@@ -24,6 +35,43 @@ static PLUGIN_BUILDER_REGISTRY: Lazy<HashMap<&'static str, PluginInitFn>> = Lazy
             cms_rate::CMS_RATE_PLUGIN_NAME,
             Arc::new(cms_rate::create_cms_rate_limiter),
         ),
+        (cors::CORS_PLUGIN_NAME, Arc::new(cors::create_cors_plugin)),
+        (
+            direct_response::DIRECT_RESPONSE_PLUGIN_NAME,
+            Arc::new(direct_response::create_direct_response_plugin),
+        ),
+        (
+            fault_injection::FAULT_INJECTION_PLUGIN_NAME,
+            Arc::new(fault_injection::create_fault_injection_plugin),
+        ),
+        (
+            headers::HEADERS_PLUGIN_NAME,
+            Arc::new(headers::create_headers_plugin),
+        ),
+        (
+            ip_restriction::IP_RESTRICTION_PLUGIN_NAME,
+            Arc::new(ip_restriction::create_ip_restriction_plugin),
+        ),
+        (
+            limit_req::LIMIT_REQ_PLUGIN_NAME,
+            Arc::new(limit_req::create_limit_req_plugin),
+        ),
+        (
+            limit_bandwidth::LIMIT_BANDWIDTH_PLUGIN_NAME,
+            Arc::new(limit_bandwidth::create_limit_bandwidth_plugin),
+        ),
+        (
+            limit_conn::LIMIT_CONN_PLUGIN_NAME,
+            Arc::new(limit_conn::create_limit_conn_plugin),
+        ),
+        (
+            redis_rate::REDIS_RATE_PLUGIN_NAME,
+            Arc::new(redis_rate::create_redis_rate_plugin),
+        ),
+        (
+            request_id::REQUEST_ID_PLUGIN_NAME,
+            Arc::new(request_id::create_request_id_plugin),
+        ),
     ];
     arr.into_iter().collect()
 });
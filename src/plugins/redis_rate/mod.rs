@@ -0,0 +1,482 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::warn;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use pingora::{http::ResponseHeader, prelude::*};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, RedisResult, Script,
+};
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use tokio::sync::OnceCell;
+use validator::Validate;
+
+use crate::{
+    core::plugin::{Plugin, PluginCtx},
+    plugins::{
+        errors::*,
+        limit::{Decision, Quota, QuotaConf, RateLimitStatus, Rejection, RejectionConf, MICROS},
+        PluginResult,
+    },
+    utils::template::Template,
+};
+
+pub const REDIS_RATE_PLUGIN_NAME: &str = "redis_rate";
+
+/// GCRA over the theoretical arrival time stored in `KEYS[1]`, in microseconds of the redis clock
+/// so that all gateway instances agree on time
+///
+/// Takes up to `ARGV[3]` requests off the quota and returns
+/// `{granted, remaining, reset_us, retry_after_us}`. The TAT is stored with `%d`, Lua would print
+/// such large numbers in scientific notation and lose precision.
+static GCRA_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+local t = redis.call('TIME')
+local now = tonumber(t[1]) * 1000000 + tonumber(t[2])
+local emission = tonumber(ARGV[1])
+local tolerance = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local tat = tonumber(redis.call('GET', KEYS[1]) or now)
+if tat < now then
+    tat = now
+end
+local available = math.floor((now + tolerance + emission - tat) / emission)
+if available < 1 then
+    return {0, 0, tat - now, tat - tolerance - now}
+end
+local granted = math.min(cost, available)
+local new_tat = tat + granted * emission
+redis.call('SET', KEYS[1], string.format('%d', new_tat), 'PX', math.ceil((new_tat - now) / 1000) + 1)
+return {granted, available - granted, new_tat - now, math.max(new_tat - tolerance - now, 0)}
+"#,
+    )
+});
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct RedisRateConf {
+    /// e.g. `redis://:password@127.0.0.1:6379/0`
+    pub url: String,
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub quota: QuotaConf,
+    /// Template of the key requests are limited by, `${client_ip}` by default
+    pub key: Option<String>,
+    /// Prefix of the redis keys, routes sharing a prefix share the quotas of their keys
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Quotas of specific keys, overriding the default one
+    #[serde(default)]
+    #[validate(nested)]
+    pub overrides: HashMap<String, QuotaConf>,
+    /// Requests taken off the quota per round trip, the ones not used right away are spent
+    /// locally within `batch_ttl`
+    #[serde(default = "default_batch")]
+    #[validate(range(min = 1))]
+    pub batch: u64,
+    #[serde(default = "default_batch_ttl", with = "humantime_serde")]
+    pub batch_ttl: Duration,
+    /// Maximum number of keys holding local batches
+    #[serde(default = "default_max_keys")]
+    #[validate(range(min = 1))]
+    pub max_keys: usize,
+    /// Timeout of the redis calls
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Whether requests are allowed or rejected when redis is unreachable
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    /// Whether to send the `X-RateLimit-*` and `Retry-After` headers
+    #[serde(default = "default_headers")]
+    pub headers: bool,
+    #[serde(default)]
+    pub rejection: RejectionConf,
+    /// Only logs the requests that would be rejected
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Allow requests
+    #[default]
+    Open,
+    /// Reject requests
+    Closed,
+}
+
+fn default_prefix() -> String {
+    "penguin:redis_rate:".to_string()
+}
+
+fn default_batch() -> u64 {
+    1
+}
+
+fn default_batch_ttl() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_max_keys() -> usize {
+    10_000
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_headers() -> bool {
+    true
+}
+
+pub fn create_redis_rate_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg = cfg.ok_or(PluginError::LackPluginConfig {
+        name: REDIS_RATE_PLUGIN_NAME.to_string(),
+    })?;
+    let cfg: RedisRateConf = serde_yaml::from_value(cfg).context(YamlErrSnafu {
+        name: REDIS_RATE_PLUGIN_NAME.to_string(),
+    })?;
+    cfg.validate().context(ValidateErrSnafu {
+        name: REDIS_RATE_PLUGIN_NAME.to_string(),
+    })?;
+    let key =
+        Template::parse(cfg.key.as_deref().unwrap_or("${client_ip}")).context(TemplateSnafu {
+            name: REDIS_RATE_PLUGIN_NAME.to_string(),
+        })?;
+    let client = Client::open(cfg.url.as_str())
+        .map_err(|e| e.into())
+        .context(SpecificErrSnafu {
+            name: REDIS_RATE_PLUGIN_NAME.to_string(),
+        })?;
+    let max_keys = NonZeroUsize::new(cfg.max_keys).unwrap_or(NonZeroUsize::MIN);
+    Ok(Box::new(RedisRatePlugin {
+        inner: Arc::new(RedisRate {
+            client,
+            connection: OnceCell::new(),
+            key,
+            prefix: cfg.prefix,
+            quota: Quota::new(&cfg.quota, MICROS),
+            overrides: cfg
+                .overrides
+                .iter()
+                .map(|(key, quota)| (key.clone(), Quota::new(quota, MICROS)))
+                .collect(),
+            batch: cfg.batch,
+            batch_ttl: cfg.batch_ttl,
+            leases: Mutex::new(LruCache::new(max_keys)),
+            timeout: cfg.timeout,
+            failure_policy: cfg.failure_policy,
+            headers: cfg.headers,
+            rejection: Rejection::new(cfg.rejection, REDIS_RATE_PLUGIN_NAME)?,
+            dry_run: cfg.dry_run,
+        }),
+    }))
+}
+
+/// Requests taken off the quota in redis but not used yet
+struct Lease {
+    tokens: u64,
+    /// Remaining quota in redis when the lease was granted
+    remaining: u64,
+    reset: u64,
+    /// Seconds until redis allows requests again once the lease and the remaining quota are
+    /// spent
+    retry_after: u64,
+    expires_at: Instant,
+}
+
+/// Outcome of [`RedisRate::check`]
+enum Check {
+    Decided(Decision),
+    /// Redis could not be reached in time, the request is rejected if the failure policy is
+    /// closed
+    Failed {
+        reject: bool,
+    },
+}
+
+struct RedisRate {
+    client: Client,
+    /// Connected on first use, reconnects automatically once established
+    connection: OnceCell<ConnectionManager>,
+    key: Template,
+    prefix: String,
+    quota: Quota,
+    overrides: HashMap<String, Quota>,
+    batch: u64,
+    batch_ttl: Duration,
+    leases: Mutex<LruCache<String, Lease>>,
+    timeout: Duration,
+    failure_policy: FailurePolicy,
+    headers: bool,
+    rejection: Rejection,
+    dry_run: bool,
+}
+
+impl RedisRate {
+    async fn connection(&self) -> RedisResult<ConnectionManager> {
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(self.timeout)
+            .set_response_timeout(self.timeout)
+            .set_number_of_retries(1);
+        self.connection
+            .get_or_try_init(|| ConnectionManager::new_with_config(self.client.clone(), config))
+            .await
+            .cloned()
+    }
+
+    /// Spends a request of the local lease of the key if there is one
+    fn take_lease(&self, key: &str, quota: &Quota) -> Option<Decision> {
+        let mut leases = self.leases.lock().unwrap();
+        let lease = leases.get_mut(key)?;
+        if lease.tokens == 0 || lease.expires_at <= Instant::now() {
+            leases.pop(key);
+            return None;
+        }
+        lease.tokens -= 1;
+        let remaining = lease.remaining + lease.tokens;
+        Some(Decision {
+            allowed: true,
+            status: RateLimitStatus {
+                limit: quota.limit,
+                remaining,
+                reset: lease.reset,
+                retry_after: if remaining == 0 { lease.retry_after } else { 0 },
+            },
+        })
+    }
+
+    async fn acquire(&self, key: &str, quota: &Quota) -> RedisResult<Decision> {
+        if let Some(decision) = self.take_lease(key, quota) {
+            return Ok(decision);
+        }
+        let mut conn = self.connection().await?;
+        let (granted, remaining, reset, retry_after): (u64, u64, u64, u64) = GCRA_SCRIPT
+            .key(format!("{}{}", self.prefix, key))
+            .arg(quota.emission)
+            .arg(quota.tolerance)
+            .arg(self.batch)
+            .invoke_async(&mut conn)
+            .await?;
+        if granted > 1 {
+            let lease = Lease {
+                tokens: granted - 1,
+                remaining,
+                reset: quota.ceil_secs(reset),
+                retry_after: quota.ceil_secs(retry_after),
+                expires_at: Instant::now() + self.batch_ttl,
+            };
+            self.leases.lock().unwrap().put(key.to_string(), lease);
+        }
+        let remaining = remaining + granted.saturating_sub(1);
+        Ok(Decision {
+            allowed: granted > 0,
+            status: RateLimitStatus {
+                limit: quota.limit,
+                remaining,
+                reset: quota.ceil_secs(reset),
+                retry_after: if remaining == 0 {
+                    quota.ceil_secs(retry_after)
+                } else {
+                    0
+                },
+            },
+        })
+    }
+
+    /// Takes a request off the quota, falling back to the failure policy if redis can't be
+    /// reached within the timeout
+    async fn check(&self, key: &str, quota: &Quota) -> Check {
+        let reason = match tokio::time::timeout(self.timeout, self.acquire(key, quota)).await {
+            Ok(Ok(decision)) => return Check::Decided(decision),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+        warn!(
+            "redis_rate failed to reach redis, policy: {:?}, error: {}",
+            self.failure_policy, reason
+        );
+        Check::Failed {
+            reject: self.failure_policy == FailurePolicy::Closed && !self.dry_run,
+        }
+    }
+}
+
+/// Limits requests per key to a sustained rate with bursts, sharing the quotas across gateway
+/// instances through redis
+pub struct RedisRatePlugin {
+    inner: Arc<RedisRate>,
+}
+
+#[async_trait]
+impl Plugin for RedisRatePlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        let inner = &self.inner;
+        let key = inner.key.render(session, ctx);
+        let quota = inner.overrides.get(&key).unwrap_or(&inner.quota);
+        let decision = match inner.check(&key, quota).await {
+            Check::Decided(decision) => decision,
+            Check::Failed { reject: true } => {
+                let retry_after = inner.headers.then_some(1);
+                inner.rejection.send(session, None, retry_after).await?;
+                return Ok(true);
+            }
+            Check::Failed { reject: false } => return Ok(false),
+        };
+        if !decision.allowed {
+            if !inner.dry_run {
+                let status = inner.headers.then_some(decision.status);
+                inner.rejection.send(session, status, None).await?;
+                return Ok(true);
+            }
+            warn!("redis_rate would reject request, key: {}", key);
+        }
+        if inner.headers {
+            decision.status.record(ctx);
+        }
+        Ok(false)
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if !self.inner.headers {
+            return Ok(());
+        }
+        RateLimitStatus::apply(ctx, upstream_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redis_rate(cfg: &str) -> RedisRate {
+        let cfg: RedisRateConf = serde_yaml::from_str(cfg).unwrap();
+        RedisRate {
+            client: Client::open(cfg.url.as_str()).unwrap(),
+            connection: OnceCell::new(),
+            key: Template::parse("${client_ip}").unwrap(),
+            prefix: cfg.prefix,
+            quota: Quota::new(&cfg.quota, MICROS),
+            overrides: HashMap::new(),
+            batch: cfg.batch,
+            batch_ttl: cfg.batch_ttl,
+            leases: Mutex::new(LruCache::new(NonZeroUsize::MIN)),
+            timeout: cfg.timeout,
+            failure_policy: cfg.failure_policy,
+            headers: cfg.headers,
+            rejection: Rejection::new(cfg.rejection, REDIS_RATE_PLUGIN_NAME).unwrap(),
+            dry_run: cfg.dry_run,
+        }
+    }
+
+    /// No redis server listens on port 1, connections are refused
+    const UNREACHABLE: &str = "{url: \"redis://127.0.0.1:1\", rate: 3, burst: 2}";
+
+    #[test]
+    fn leases_are_spent_locally_until_used_up_or_expired() {
+        let limiter = redis_rate(UNREACHABLE);
+        let quota = limiter.quota;
+        assert!(limiter.take_lease("k", &quota).is_none());
+        let lease = |expires_at| Lease {
+            tokens: 2,
+            remaining: 0,
+            reset: 3,
+            retry_after: 1,
+            expires_at,
+        };
+        let in_a_second = Instant::now() + Duration::from_secs(1);
+        limiter
+            .leases
+            .lock()
+            .unwrap()
+            .put("k".to_string(), lease(in_a_second));
+        let statuses: Vec<_> = (0..3)
+            .map(|_| limiter.take_lease("k", &quota).map(|d| d.status))
+            .map(|status| status.map(|s| (s.limit, s.remaining, s.reset, s.retry_after)))
+            .collect();
+        assert_eq!(statuses, [Some((3, 1, 3, 0)), Some((3, 0, 3, 1)), None]);
+        assert!(limiter.leases.lock().unwrap().is_empty());
+
+        limiter
+            .leases
+            .lock()
+            .unwrap()
+            .put("k".to_string(), lease(Instant::now()));
+        assert!(limiter.take_lease("k", &quota).is_none());
+        assert!(limiter.leases.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_follows_the_failure_policy() {
+        let reject = |cfg: &'static str| async move {
+            let limiter = redis_rate(cfg);
+            match limiter.check("k", &limiter.quota).await {
+                Check::Decided(_) => panic!("redis is unreachable"),
+                Check::Failed { reject } => reject,
+            }
+        };
+        assert!(!reject(UNREACHABLE).await);
+        assert!(reject("{url: \"redis://127.0.0.1:1\", rate: 3, failure_policy: closed}").await);
+        assert!(
+            !reject(
+                "{url: \"redis://127.0.0.1:1\", rate: 3, failure_policy: closed, dry_run: true}"
+            )
+            .await
+        );
+    }
+
+    /// Runs the script against the redis server of `REDIS_URL`
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn script_keeps_the_tat_exact() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let client = Client::open(url).unwrap();
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+        let key = format!("penguin_test_{}", std::process::id());
+        let quota = Quota {
+            emission: 1_000_000,
+            tolerance: 2_000_000,
+            limit: 3,
+            ticks_per_sec: MICROS,
+        };
+        let mut granted = vec![];
+        for _ in 0..4 {
+            let (g, _, _, _): (u64, u64, u64, u64) = GCRA_SCRIPT
+                .key(&key)
+                .arg(quota.emission)
+                .arg(quota.tolerance)
+                .arg(1)
+                .invoke_async(&mut conn)
+                .await
+                .unwrap();
+            granted.push(g);
+        }
+        assert_eq!(granted, [1, 1, 1, 0]);
+        let tat: String = redis::cmd("GET")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        assert!(tat.parse::<u64>().is_ok(), "tat stored as {}", tat);
+        let _: () = redis::cmd("DEL")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use http::{HeaderName, HeaderValue};
+use pingora::{http::ResponseHeader, prelude::*};
+use serde::Deserialize;
+use serde_yaml::Value as YamlValue;
+use snafu::ResultExt;
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::{
+    core::{
+        plugin::{Plugin, PluginCtx},
+        response_headers::add_response_header,
+    },
+    plugins::{errors::*, PluginResult},
+};
+
+pub const REQUEST_ID_PLUGIN_NAME: &str = "request_id";
+
+/// Longest incoming id that is reused, longer ones are replaced
+const MAX_ID_LEN: usize = 128;
+
+/// Returns whether an incoming id is safe to reuse in headers and logs, i.e. made of at most
+/// [`MAX_ID_LEN`] characters among `A-Za-z0-9._:-`
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b':' | b'-'))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RequestIdConf {
+    /// Header carrying the id, both upstream and downstream
+    pub header: String,
+    pub generator: Generator,
+    /// Whether to reuse the id of the incoming request
+    pub trust_incoming: bool,
+    /// Whether to send the id back in the response
+    pub echo: bool,
+}
+
+impl Default for RequestIdConf {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".to_string(),
+            generator: Generator::default(),
+            trust_incoming: true,
+            echo: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Generator {
+    /// Random UUID, e.g. `4f1c0a4e-5b8e-4e47-9a43-2f0a61b9c5d2`
+    #[default]
+    Uuid,
+    /// Lexicographically sortable by time, e.g. `01JAB8X6K5Q2M7V3RS0YF4C9TD`
+    Ulid,
+}
+
+impl Generator {
+    fn generate(self) -> String {
+        match self {
+            Generator::Uuid => Uuid::new_v4().to_string(),
+            Generator::Ulid => Ulid::new().to_string(),
+        }
+    }
+}
+
+pub fn create_request_id_plugin(cfg: Option<YamlValue>) -> PluginResult<Box<dyn Plugin>> {
+    let cfg: RequestIdConf = match cfg {
+        Some(cfg) => serde_yaml::from_value(cfg).context(YamlErrSnafu {
+            name: REQUEST_ID_PLUGIN_NAME.to_string(),
+        })?,
+        None => RequestIdConf::default(),
+    };
+    let header = HeaderName::from_str(&cfg.header)
+        .map_err(|e| e.into())
+        .context(SpecificErrSnafu {
+            name: REQUEST_ID_PLUGIN_NAME.to_string(),
+        })?;
+    Ok(Box::new(RequestIdPlugin {
+        header,
+        generator: cfg.generator,
+        trust_incoming: cfg.trust_incoming,
+        echo: cfg.echo,
+    }))
+}
+
+/// Assigns an id to each request, forwards it upstream and sends it back to the client
+pub struct RequestIdPlugin {
+    header: HeaderName,
+    generator: Generator,
+    trust_incoming: bool,
+    echo: bool,
+}
+
+#[async_trait]
+impl Plugin for RequestIdPlugin {
+    async fn request_filter(&self, session: &mut Session, ctx: &mut PluginCtx) -> Result<bool> {
+        if ctx.request_id.is_some() {
+            return Ok(false);
+        }
+        let incoming = self
+            .trust_incoming
+            .then(|| session.req_header().headers.get(&self.header))
+            .flatten()
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid_id(id));
+        let id = match incoming {
+            Some(id) => id.to_string(),
+            None => self.generator.generate(),
+        };
+        if self.echo {
+            // also echoed on the responses generated by the gateway, e.g. 404s and rejections
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                add_response_header(session, self.header.clone(), value);
+            }
+        }
+        ctx.request_id = Some(id);
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if let Some(id) = &ctx.request_id {
+            upstream_request.insert_header(self.header.clone(), id)?;
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut PluginCtx,
+    ) -> Result<()> {
+        if !self.echo {
+            return Ok(());
+        }
+        if let Some(id) = &ctx.request_id {
+            upstream_response.insert_header(self.header.clone(), id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incoming_ids_are_validated() {
+        assert!(is_valid_id("4f1c0a4e-5b8e-4e47-9a43-2f0a61b9c5d2"));
+        assert!(is_valid_id("01JAB8X6K5Q2M7V3RS0YF4C9TD"));
+        assert!(is_valid_id("trace:span.1_a"));
+        assert!(!is_valid_id(""));
+        assert!(!is_valid_id("id with spaces"));
+        assert!(!is_valid_id("id\"injected"));
+        assert!(!is_valid_id("idé"));
+        assert!(is_valid_id(&"a".repeat(MAX_ID_LEN)));
+        assert!(!is_valid_id(&"a".repeat(MAX_ID_LEN + 1)));
+    }
+}
@@ -1,21 +1,38 @@
 use std::borrow::Cow;
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::StatusCode;
-use log::{error, info, log_enabled, Level};
+use http::{HeaderName, StatusCode};
+use log::{error, info, log_enabled, warn, Level};
 use matchit::{InsertError, Router};
 use once_cell::sync::Lazy;
-use pingora::{http::ResponseHeader, prelude::*, proxy::ProxyHttp};
+use pingora::{
+    http::ResponseHeader,
+    modules::http::{compression::ResponseCompressionBuilder, HttpModules},
+    prelude::*,
+    protocols::http::ServerSession,
+    proxy::{FailToProxy, ProxyHttp},
+};
 use regex::Regex;
 
 use crate::{
     clusters::ClusterManager,
-    core::plugin::{Plugin, PluginCtx, RouteParams},
-    utils::send_response,
+    config::def::Timeouts,
+    core::{
+        plugin::{Plugin, PluginCtx, RouteParams},
+        response_headers::{ResponseHeaders, ResponseHeadersBuilder},
+    },
+    utils::{client_cert, send_response},
 };
 
+/// Plugins of each identity, indexed by identity name
+pub type ConsumerPlugins = HashMap<String, Arc<Vec<Box<dyn Plugin>>>>;
+
 /// Represents the main proxy structure
 pub struct Proxy {
     plugins: Vec<Box<dyn Plugin>>,
@@ -23,6 +40,10 @@ pub struct Proxy {
     matcher: MatchEntry,
     /// Manager for handling clusters of backends
     cluster_manager: ClusterManager,
+    /// Plugins of the identities, indexed by identity name
+    consumer_plugins: Arc<ConsumerPlugins>,
+    /// Whether a listener verifies client certificates, the certificate isn't parsed otherwise
+    client_certs: bool,
 }
 
 impl Proxy {
@@ -32,39 +53,108 @@ impl Proxy {
     ///
     /// * `matcher` - The MatchEntry for routing requests
     /// * `cluster_manager` - The ClusterManager for handling backend clusters
+    /// * `plugins` - Plugins applied to all routes
+    /// * `consumer_plugins` - Plugins of the identities
+    /// * `client_certs` - Whether a listener verifies client certificates
     pub fn new(
         matcher: MatchEntry,
         cluster_manager: ClusterManager,
         plugins: Vec<Box<dyn Plugin>>,
+        consumer_plugins: Arc<ConsumerPlugins>,
+        client_certs: bool,
     ) -> Self {
         Self {
             matcher,
             cluster_manager,
             plugins,
+            consumer_plugins,
+            client_certs,
         }
     }
 }
 
 static NOT_FOUND: Lazy<Bytes> = Lazy::new(|| Bytes::from("not found"));
 
+/// Error raised when the deadline of a request is exceeded
+const DEADLINE_EXCEEDED: ErrorType = ErrorType::Custom("DeadlineExceeded");
+
 /// Context for the proxy, holding plugins and other request-specific data
 #[derive(Default)]
 pub struct ProxyCtx {
     /// List of plugins to be applied
     plugins: Arc<Vec<Box<dyn Plugin>>>,
+    /// Plugins of the authenticated identity, applied after `plugins`
+    consumer_plugins: Arc<Vec<Box<dyn Plugin>>>,
     /// The selected cluster for the request
     cluster: Option<String>,
     /// Context for plugin execution
     plugin_ctx: PluginCtx,
+    /// When the request header was read, which is when the context is created
+    started_at: Option<Instant>,
+    /// Deadline of the whole request, derived from the route or cluster timeout
+    deadline: Option<Instant>,
+    /// Max time to wait on a single upstream read or write
+    idle_timeout: Option<Duration>,
+    /// Whether the response header from upstream has been received
+    upstream_responded: bool,
+}
+
+impl ProxyCtx {
+    /// Returns the time left before the deadline, if there's one
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Describes which phase of the request a timeout error occurred in
+    fn timeout_phase(&self, e: &Error) -> Option<&'static str> {
+        match e.etype() {
+            ErrorType::ReadTimedout if e.esource() == &ErrorSource::Downstream => {
+                Some("downstream request body")
+            }
+            ErrorType::WriteTimedout if e.esource() == &ErrorSource::Downstream => {
+                Some("downstream response")
+            }
+            ErrorType::ConnectTimedout => Some("upstream connect"),
+            ErrorType::WriteTimedout if e.esource() == &ErrorSource::Upstream => {
+                Some("upstream request")
+            }
+            ErrorType::ReadTimedout if e.esource() == &ErrorSource::Upstream => {
+                if self.upstream_responded {
+                    Some("response streaming")
+                } else {
+                    Some("upstream response header")
+                }
+            }
+            ErrorType::Custom(_) if e.etype() == &DEADLINE_EXCEEDED => {
+                if self.upstream_responded {
+                    Some("response streaming")
+                } else {
+                    Some("upstream connect")
+                }
+            }
+            _ => None,
+        }
+    }
 }
 
 #[async_trait]
 impl ProxyHttp for Proxy {
     type CTX = ProxyCtx;
 
+    /// Adds the modules filtering all responses sent downstream
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        // disabled by default, as in Pingora
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+        modules.add_module(Box::new(ResponseHeadersBuilder));
+    }
+
     /// Creates a new context for each request
     fn new_ctx(&self) -> Self::CTX {
-        Self::CTX::default()
+        Self::CTX {
+            started_at: Some(Instant::now()),
+            ..Default::default()
+        }
     }
 
     /// Filters incoming requests
@@ -75,6 +165,10 @@ impl ProxyHttp for Proxy {
     where
         Self::CTX: Send + Sync,
     {
+        if self.client_certs {
+            ctx.plugin_ctx.client_cert = client_cert(session).map(Arc::new);
+        }
+
         // global plugins
         for plugin in &self.plugins {
             let stop = plugin.request_filter(session, &mut ctx.plugin_ctx).await?;
@@ -84,11 +178,29 @@ impl ProxyHttp for Proxy {
         }
 
         // Match request to pipeline
-        if let Some((route_params, ppl)) = self.matcher.match_request(session) {
+        let matched = self
+            .matcher
+            .match_request(session.req_header(), &ctx.plugin_ctx);
+        if let Some((route_params, ppl)) = matched {
             ctx.cluster = Some(ppl.cluster.clone());
 
+            // Apply timeouts, the ones of the route take precedence over the cluster's
+            let timeouts = ppl
+                .timeouts
+                .or(self.cluster_manager.get_timeouts(&ppl.cluster));
+            ctx.deadline = timeouts
+                .timeout
+                .zip(ctx.started_at)
+                .map(|(timeout, started_at)| started_at + timeout);
+            if let Some(idle_timeout) = timeouts.idle_timeout {
+                ctx.idle_timeout = Some(idle_timeout);
+                session.set_read_timeout(Some(idle_timeout));
+                session.set_write_timeout(Some(idle_timeout));
+            }
+
             // Initialize plugins
             ctx.plugins = ppl.plugins.clone();
+            ctx.plugin_ctx.route = Some(ppl.name.clone());
             ctx.plugin_ctx.route_params = Some(route_params);
 
             // Apply request filters from each plugin
@@ -98,6 +210,22 @@ impl ProxyHttp for Proxy {
                     return Ok(true);
                 }
             }
+
+            // Plugins of the authenticated identity run after the route's
+            if let Some(plugins) = ctx
+                .plugin_ctx
+                .identity
+                .as_ref()
+                .and_then(|identity| self.consumer_plugins.get(identity))
+            {
+                ctx.consumer_plugins = plugins.clone();
+                for plugin in ctx.consumer_plugins.iter() {
+                    let should_stop = plugin.request_filter(session, &mut ctx.plugin_ctx).await?;
+                    if should_stop {
+                        return Ok(true);
+                    }
+                }
+            }
         } else {
             send_response(
                 session,
@@ -129,7 +257,7 @@ impl ProxyHttp for Proxy {
                 .request_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)
                 .await?;
         }
-        for plugin in ctx.plugins.iter() {
+        for plugin in ctx.plugins.iter().chain(ctx.consumer_plugins.iter()) {
             plugin
                 .request_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)
                 .await?;
@@ -152,7 +280,7 @@ impl ProxyHttp for Proxy {
                 .upstream_request_filter(session, upstream_request, &mut ctx.plugin_ctx)
                 .await?;
         }
-        for plugin in ctx.plugins.iter() {
+        for plugin in ctx.plugins.iter().chain(ctx.consumer_plugins.iter()) {
             plugin
                 .upstream_request_filter(session, upstream_request, &mut ctx.plugin_ctx)
                 .await?;
@@ -169,13 +297,14 @@ impl ProxyHttp for Proxy {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        ctx.upstream_responded = true;
         // global plugins
         for plugin in self.plugins.iter() {
             plugin
                 .response_filter(session, upstream_response, &mut ctx.plugin_ctx)
                 .await?;
         }
-        for plugin in ctx.plugins.iter() {
+        for plugin in ctx.plugins.iter().chain(ctx.consumer_plugins.iter()) {
             plugin
                 .response_filter(session, upstream_response, &mut ctx.plugin_ctx)
                 .await?;
@@ -193,14 +322,26 @@ impl ProxyHttp for Proxy {
         end_of_stream: bool,
         ctx: &mut Self::CTX,
     ) -> Result<Option<Duration>> {
+        if ctx.remaining().is_some_and(|r| r.is_zero()) {
+            return Error::e_explain(
+                DEADLINE_EXCEEDED,
+                "request deadline exceeded while streaming response",
+            );
+        }
+        // The longest delay asked by the plugins wins
+        let mut delay = None;
         // global plugins
         for plugin in self.plugins.iter() {
-            plugin.response_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)?;
+            let d =
+                plugin.response_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)?;
+            delay = delay.max(d);
         }
-        for plugin in ctx.plugins.iter() {
-            plugin.response_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)?;
+        for plugin in ctx.plugins.iter().chain(ctx.consumer_plugins.iter()) {
+            let d =
+                plugin.response_body_filter(session, body, end_of_stream, &mut ctx.plugin_ctx)?;
+            delay = delay.max(d);
         }
-        Ok(None)
+        Ok(delay)
     }
 
     /// This filter is called when the entire response is sent to the downstream successfully or
@@ -208,10 +349,17 @@ impl ProxyHttp for Proxy {
     ///
     /// An error log is already emitted if there is any error. This phase is used for collecting
     /// metrics and sending access logs.
-    async fn logging(&self, session: &mut Session, e: Option<&Error>, _ctx: &mut Self::CTX)
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX)
     where
         Self::CTX: Send + Sync,
     {
+        // global plugins
+        for plugin in self.plugins.iter() {
+            plugin.logging(session, e, &mut ctx.plugin_ctx).await;
+        }
+        for plugin in ctx.plugins.iter().chain(ctx.consumer_plugins.iter()) {
+            plugin.logging(session, e, &mut ctx.plugin_ctx).await;
+        }
         if log_enabled!(Level::Info) {
             let req = session.req_header();
             let resp = session.response_written();
@@ -223,14 +371,81 @@ impl ProxyHttp for Proxy {
             let remote_addr = session
                 .client_addr()
                 .map_or(Cow::Borrowed("-"), |ip| Cow::Owned(ip.to_string()));
+            let remote_user = ctx.plugin_ctx.identity.as_deref().unwrap_or("-");
+            let request_id = ctx.plugin_ctx.request_id.as_deref().unwrap_or("-");
             // 使用类似nginx 的格式打日志
             info!(
-                "{} \"{} {}\" {} {}",
-                remote_addr, req.method, req.uri, status, body_bytes_sent
+                "{} {} \"{} {}\" {} {} {}",
+                remote_addr, remote_user, req.method, req.uri, status, body_bytes_sent, request_id
             );
         }
         if let Some(e) = e {
-            error!(error:? = e; "Error occurred");
+            let request_id = ctx.plugin_ctx.request_id.as_deref().unwrap_or("-");
+            error!(request_id = request_id, error:? = e; "Error occurred");
+        }
+    }
+
+    /// Responds 504 on upstream timeouts and 408 when the client is too slow to send the body,
+    /// other errors are handled the same as Pingora's default
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = if let Some(phase) = ctx.timeout_phase(e) {
+            warn!(
+                phase = phase,
+                cluster = ctx.cluster.as_deref().unwrap_or("-");
+                "Request timed out: {}", session.request_summary()
+            );
+            match (e.esource(), e.etype()) {
+                (ErrorSource::Downstream, ErrorType::ReadTimedout) => {
+                    StatusCode::REQUEST_TIMEOUT.as_u16()
+                }
+                // the client isn't reading, so it won't read an error response either
+                (ErrorSource::Downstream, _) => 0,
+                _ => StatusCode::GATEWAY_TIMEOUT.as_u16(),
+            }
+        } else {
+            match e.etype() {
+                ErrorType::HTTPStatus(code) => *code,
+                _ => match e.esource() {
+                    ErrorSource::Upstream => 502,
+                    ErrorSource::Downstream => match e.etype() {
+                        // connection is already dead
+                        ErrorType::WriteError
+                        | ErrorType::ReadError
+                        | ErrorType::ConnectionClosed => 0,
+                        _ => 400,
+                    },
+                    ErrorSource::Internal | ErrorSource::Unset => 500,
+                },
+            }
+        };
+        // the response can't be replaced once its header is sent
+        if code > 0 && session.response_written().is_none() {
+            let mut resp = ServerSession::generate_error(code);
+            // error responses bypass the downstream modules
+            if let Some(headers) = session.downstream_modules_ctx.get::<ResponseHeaders>() {
+                headers.apply(&mut resp).unwrap_or_else(|e| {
+                    error!(error:? = e; "Failed to add headers to error response");
+                });
+            }
+            session
+                .as_downstream_mut()
+                .write_error_response(resp, Bytes::new())
+                .await
+                .unwrap_or_else(|e| {
+                    error!(error:? = e; "Failed to send error response to downstream");
+                });
+        }
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
         }
     }
 
@@ -254,31 +469,140 @@ impl ProxyHttp for Proxy {
             Error::new(ErrorType::Custom("no backend"))
                 .more_context(format!("cluster: {}", cluster)),
         )?;
-        Ok(Box::new(HttpPeer::new(backend, false, "a.b.c".to_string())))
+        let mut peer = HttpPeer::new(backend, false, "a.b.c".to_string());
+        // every upstream operation is bounded by both the idle timeout and the deadline
+        let remaining = ctx.remaining();
+        if remaining.is_some_and(|r| r.is_zero()) {
+            return Error::e_explain(
+                DEADLINE_EXCEEDED,
+                "request deadline exceeded before connecting upstream",
+            );
+        }
+        let io_timeout = match (ctx.idle_timeout, remaining) {
+            (Some(idle), Some(remaining)) => Some(idle.min(remaining)),
+            (idle, remaining) => idle.or(remaining),
+        };
+        peer.options.total_connection_timeout = remaining;
+        peer.options.read_timeout = io_timeout;
+        peer.options.write_timeout = io_timeout;
+        Ok(Box::new(peer))
     }
 }
 
 /// Represents a pipeline of plugins for a specific route
 pub struct Pipeline {
+    /// Name of the route this pipeline is built from
+    name: String,
     /// List of plugin builders for this pipeline
     plugins: Arc<Vec<Box<dyn Plugin>>>,
+    /// Names of the plugins, in the same order as `plugins`
+    plugin_names: Vec<String>,
     /// The cluster associated with this pipeline
     cluster: String,
+    /// Timeouts of the route, unset ones fall back to the cluster's
+    timeouts: Timeouts,
 }
 
 impl Pipeline {
     /// Creates a new Pipeline instance
-    pub fn new(plugins: Arc<Vec<Box<dyn Plugin>>>, cluster: String) -> Self {
-        Self { plugins, cluster }
+    pub fn new(
+        name: String,
+        plugins: Arc<Vec<Box<dyn Plugin>>>,
+        plugin_names: Vec<String>,
+        cluster: String,
+        timeouts: Timeouts,
+    ) -> Self {
+        Self {
+            name,
+            plugins,
+            plugin_names,
+            cluster,
+            timeouts,
+        }
+    }
+
+    /// Returns the name of the route
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the names of the plugins of the route
+    pub fn plugin_names(&self) -> &[String] {
+        &self.plugin_names
+    }
+
+    /// Returns the cluster the route forwards to
+    pub fn cluster(&self) -> &str {
+        &self.cluster
     }
 }
 
+/// Matcher for a header or cookie value
+pub enum ValueMatcher {
+    Exact(String),
+    Prefix(String),
+    Regexp(Regex),
+    /// Matches if the presence of the value equals the flag
+    Present(bool),
+}
+
+impl ValueMatcher {
+    fn is_match(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (ValueMatcher::Present(present), v) => *present == v.is_some(),
+            (_, None) => false,
+            (ValueMatcher::Exact(exact), Some(v)) => v == exact,
+            (ValueMatcher::Prefix(prefix), Some(v)) => v.starts_with(prefix.as_str()),
+            (ValueMatcher::Regexp(re), Some(v)) => re.is_match(v),
+        }
+    }
+}
+
+/// Extra conditions besides the uri that a request must satisfy to hit a route
+#[derive(Default)]
+pub struct RouteConditions {
+    headers: Vec<(HeaderName, ValueMatcher)>,
+    cookies: Vec<(String, ValueMatcher)>,
+}
+
+impl RouteConditions {
+    /// Creates a new RouteConditions instance
+    pub fn new(
+        headers: Vec<(HeaderName, ValueMatcher)>,
+        cookies: Vec<(String, ValueMatcher)>,
+    ) -> Self {
+        Self { headers, cookies }
+    }
+
+    fn is_match(&self, req: &RequestHeader, ctx: &PluginCtx) -> bool {
+        self.headers.iter().all(|(name, matcher)| {
+            matcher.is_match(req.headers.get(name).and_then(|v| v.to_str().ok()))
+        }) && self
+            .cookies
+            .iter()
+            .all(|(name, matcher)| matcher.is_match(ctx.cookie(req, name)))
+    }
+}
+
+/// A route candidate, the pipeline is chosen only if the conditions are met
+type Candidate = (RouteConditions, Arc<Pipeline>);
+
 /// Struct for matching requests to pipelines
+///
+/// Routes sharing the same uri are tried in the order they are added. When none of the routes
+/// of the most specific uri pattern meets its conditions, the other uri patterns matching the
+/// request are tried from the most to the least specific, then the regex routes.
 pub struct MatchEntry {
-    /// Router for non-regex URI matching
-    non_reg_uri: Router<Arc<Pipeline>>,
+    /// Router for non-regex URI matching, the value is the index into `uri_candidates`
+    non_reg_uri: Router<usize>,
+    /// Candidates of each non-regex uri pattern
+    uri_candidates: Vec<Vec<Candidate>>,
+    /// Index into `uri_candidates` of each inserted uri pattern
+    uri_patterns: HashMap<String, usize>,
+    /// A router of its own for each uri pattern, from the most to the least specific
+    fallbacks: Vec<(Router<usize>, Specificity)>,
     /// Vector of regex patterns and associated pipelines
-    regex_uris: Vec<(Regex, Arc<Pipeline>)>,
+    regex_uris: Vec<(Regex, Candidate)>,
 }
 
 impl MatchEntry {
@@ -286,41 +610,250 @@ impl MatchEntry {
     pub fn new() -> Self {
         Self {
             non_reg_uri: Router::new(),
+            uri_candidates: vec![],
+            uri_patterns: HashMap::new(),
+            fallbacks: vec![],
             regex_uris: vec![],
         }
     }
 
     /// Inserts a new route into the non-regex router
-    pub fn insert_route(&mut self, path: &str, ppl: Arc<Pipeline>) -> Result<(), InsertError> {
-        if self.non_reg_uri.at(path).is_ok() {
+    pub fn insert_route(
+        &mut self,
+        path: &str,
+        conditions: RouteConditions,
+        ppl: Arc<Pipeline>,
+    ) -> Result<(), InsertError> {
+        if let Some(idx) = self.uri_patterns.get(path) {
+            self.uri_candidates[*idx].push((conditions, ppl));
             return Ok(());
         }
-        self.non_reg_uri.insert(path, ppl)
+        let idx = self.uri_candidates.len();
+        self.non_reg_uri.insert(path, idx)?;
+        let mut router = Router::new();
+        router.insert(path, idx)?;
+        self.fallbacks.push((router, Specificity::of(path)));
+        // stable, patterns as specific as each other keep their order
+        self.fallbacks.sort_by_key(|(_, specificity)| *specificity);
+        self.uri_candidates.push(vec![(conditions, ppl)]);
+        self.uri_patterns.insert(path.to_string(), idx);
+        Ok(())
+    }
+
+    /// Returns the first route of the uri pattern whose conditions the request meets
+    fn candidate(
+        &self,
+        idx: usize,
+        req: &RequestHeader,
+        ctx: &PluginCtx,
+    ) -> Option<&Arc<Pipeline>> {
+        self.uri_candidates[idx]
+            .iter()
+            .find(|(conditions, _)| conditions.is_match(req, ctx))
+            .map(|(_, ppl)| ppl)
     }
 
     /// Adds a new regex route
-    pub fn add_regex_route(&mut self, re: Regex, ppl: Arc<Pipeline>) {
-        self.regex_uris.push((re, ppl));
+    pub fn add_regex_route(&mut self, re: Regex, conditions: RouteConditions, ppl: Arc<Pipeline>) {
+        self.regex_uris.push((re, (conditions, ppl)));
     }
 
     /// Matches a request to a pipeline
-    fn match_request(&self, session: &mut Session) -> Option<(RouteParams, Arc<Pipeline>)> {
-        let uri = session.req_header().uri.path();
-        if let Ok(ppl) = self.non_reg_uri.at(uri) {
-            return Some((RouteParams::new_params(&ppl.params), ppl.value.clone()));
+    ///
+    /// `ctx` is used to cache the parsed cookies of the request.
+    pub fn match_request(
+        &self,
+        req: &RequestHeader,
+        ctx: &PluginCtx,
+    ) -> Option<(RouteParams, Arc<Pipeline>)> {
+        let uri = req.uri.path();
+        if let Ok(matched) = self.non_reg_uri.at(uri) {
+            let best = *matched.value;
+            if let Some(ppl) = self.candidate(best, req, ctx) {
+                return Some((RouteParams::new_params(&matched.params), ppl.clone()));
+            }
+            for (router, _) in &self.fallbacks {
+                let Ok(matched) = router.at(uri) else {
+                    continue;
+                };
+                if *matched.value == best {
+                    continue;
+                }
+                if let Some(ppl) = self.candidate(*matched.value, req, ctx) {
+                    return Some((RouteParams::new_params(&matched.params), ppl.clone()));
+                }
+            }
         }
 
-        for (re, ppl) in self.regex_uris.iter() {
+        for (re, (conditions, ppl)) in self.regex_uris.iter() {
             if let Some(caps) = re.captures(uri) {
-                return Some((RouteParams::new_caps(&caps), ppl.clone()));
+                if conditions.is_match(req, ctx) {
+                    return Some((RouteParams::new_caps(re, &caps), ppl.clone()));
+                }
             }
         }
         None
     }
 }
 
+/// Orders uri patterns from the most to the least specific: patterns without catch-all first,
+/// then by decreasing length of their static prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Specificity {
+    catch_all: bool,
+    static_len: std::cmp::Reverse<usize>,
+}
+
+impl Specificity {
+    fn of(path: &str) -> Self {
+        Self {
+            catch_all: path.contains("{*"),
+            static_len: std::cmp::Reverse(path.find('{').unwrap_or(path.len())),
+        }
+    }
+}
+
 impl Default for MatchEntry {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(name: &str) -> Arc<Pipeline> {
+        Arc::new(Pipeline::new(
+            name.to_string(),
+            Arc::new(vec![]),
+            vec![],
+            "c".to_string(),
+            Timeouts::default(),
+        ))
+    }
+
+    fn cookie(name: &str, value: &str) -> RouteConditions {
+        RouteConditions::new(
+            vec![],
+            vec![(name.to_string(), ValueMatcher::Exact(value.to_string()))],
+        )
+    }
+
+    fn route(matcher: &MatchEntry, path: &str, cookies: Option<&str>) -> Option<String> {
+        let mut req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        if let Some(cookies) = cookies {
+            req.insert_header("cookie", cookies).unwrap();
+        }
+        matcher
+            .match_request(&req, &PluginCtx::default())
+            .map(|(_, ppl)| ppl.name().to_string())
+    }
+
+    #[test]
+    fn routes_of_a_pattern_are_tried_in_order() {
+        let mut matcher = MatchEntry::new();
+        matcher
+            .insert_route("/api/x", cookie("beta", "1"), pipeline("beta"))
+            .unwrap();
+        matcher
+            .insert_route("/api/x", RouteConditions::default(), pipeline("stable"))
+            .unwrap();
+        assert_eq!(
+            route(&matcher, "/api/x", Some("beta=1")).as_deref(),
+            Some("beta")
+        );
+        assert_eq!(
+            route(&matcher, "/api/x", Some("beta=2")).as_deref(),
+            Some("stable")
+        );
+        assert_eq!(route(&matcher, "/api/x", None).as_deref(), Some("stable"));
+    }
+
+    #[test]
+    fn less_specific_patterns_are_tried_when_conditions_fail() {
+        let mut matcher = MatchEntry::new();
+        matcher
+            .insert_route("/api/x", cookie("beta", "1"), pipeline("beta"))
+            .unwrap();
+        matcher
+            .insert_route("/api/{id}", cookie("tenant", "a"), pipeline("tenant"))
+            .unwrap();
+        matcher
+            .insert_route("/{*rest}", RouteConditions::default(), pipeline("root"))
+            .unwrap();
+        matcher
+            .insert_route("/api{*rest}", RouteConditions::default(), pipeline("api"))
+            .unwrap();
+        assert_eq!(
+            route(&matcher, "/api/x", Some("beta=1")).as_deref(),
+            Some("beta")
+        );
+        assert_eq!(
+            route(&matcher, "/api/x", Some("tenant=a")).as_deref(),
+            Some("tenant")
+        );
+        assert_eq!(route(&matcher, "/api/x", None).as_deref(), Some("api"));
+        assert_eq!(route(&matcher, "/api/y", None).as_deref(), Some("api"));
+        assert_eq!(route(&matcher, "/other", None).as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn regex_routes_come_after_all_uri_patterns() {
+        let mut matcher = MatchEntry::new();
+        matcher
+            .insert_route("/api/x", cookie("beta", "1"), pipeline("beta"))
+            .unwrap();
+        matcher.add_regex_route(
+            Regex::new("^/api/.*").unwrap(),
+            RouteConditions::default(),
+            pipeline("regex"),
+        );
+        assert_eq!(
+            route(&matcher, "/api/x", Some("beta=1")).as_deref(),
+            Some("beta")
+        );
+        assert_eq!(route(&matcher, "/api/x", None).as_deref(), Some("regex"));
+        assert_eq!(route(&matcher, "/nope", None), None);
+    }
+
+    #[test]
+    fn value_matchers() {
+        assert!(ValueMatcher::Exact("a".to_string()).is_match(Some("a")));
+        assert!(!ValueMatcher::Exact("a".to_string()).is_match(Some("ab")));
+        assert!(ValueMatcher::Prefix("a".to_string()).is_match(Some("ab")));
+        assert!(!ValueMatcher::Prefix("a".to_string()).is_match(None));
+        assert!(ValueMatcher::Regexp(Regex::new("^v\\d$").unwrap()).is_match(Some("v1")));
+        assert!(ValueMatcher::Present(true).is_match(Some("")));
+        assert!(ValueMatcher::Present(false).is_match(None));
+        assert!(!ValueMatcher::Present(false).is_match(Some("x")));
+    }
+
+    #[test]
+    fn timeouts_are_labeled_by_phase() {
+        let mut ctx = ProxyCtx::default();
+        let phase = |ctx: &ProxyCtx, e: Box<Error>| ctx.timeout_phase(&e);
+        assert_eq!(
+            phase(&ctx, Error::new_down(ErrorType::ReadTimedout)),
+            Some("downstream request body")
+        );
+        assert_eq!(
+            phase(&ctx, Error::new_down(ErrorType::WriteTimedout)),
+            Some("downstream response")
+        );
+        assert_eq!(
+            phase(&ctx, Error::new_up(ErrorType::ConnectTimedout)),
+            Some("upstream connect")
+        );
+        assert_eq!(
+            phase(&ctx, Error::new_up(ErrorType::ReadTimedout)),
+            Some("upstream response header")
+        );
+        ctx.upstream_responded = true;
+        assert_eq!(
+            phase(&ctx, Error::new_up(ErrorType::ReadTimedout)),
+            Some("response streaming")
+        );
+        assert_eq!(phase(&ctx, Error::new_up(ErrorType::ConnectRefused)), None);
+    }
+}
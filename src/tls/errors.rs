@@ -0,0 +1,23 @@
+use openssl::error::ErrorStack;
+use pingora::BError;
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum TlsError {
+    #[snafu(display("Failed to read {}, error: {}", path, source))]
+    ReadFile {
+        source: std::io::Error,
+        path: String,
+    },
+    #[snafu(display("Invalid pem {}, error: {}", path, source))]
+    InvalidPem { source: ErrorStack, path: String },
+    #[snafu(display("No certificate in {}", path))]
+    EmptyCert { path: String },
+    #[snafu(display("Private key doesn't match certificate {}", path))]
+    KeyMismatch { path: String },
+    #[snafu(display("Invalid {}, error: {}", setting, source))]
+    Setting { source: ErrorStack, setting: String },
+    #[snafu(display("Failed to create tls settings, error: {}", source))]
+    Pingora { source: BError },
+}
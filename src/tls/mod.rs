@@ -0,0 +1,354 @@
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info};
+use openssl::{
+    pkey::{PKey, Private},
+    ssl::{NameType, SslRef, SslVerifyMode, SslVersion},
+    x509::{X509Name, X509},
+};
+use pingora::{
+    listeners::{tls::TlsSettings, TlsAccept},
+    server::ShutdownWatch,
+    services::background::BackgroundService,
+    tls::ext,
+};
+use snafu::ResultExt;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::MissedTickBehavior,
+};
+
+use crate::config::def::{ClientVerify, SslConfig, TlsVersion};
+use errors::*;
+
+pub mod errors;
+
+pub type TlsResult<T> = Result<T, errors::TlsError>;
+
+/// A certificate along with its chain and private key
+pub struct CertKey {
+    leaf: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl CertKey {
+    /// Loads a PEM certificate chain and its private key, failing if they don't match
+    pub fn load(cert_path: &str, key_path: &str) -> TlsResult<Self> {
+        let pem = std::fs::read(cert_path).context(ReadFileSnafu { path: cert_path })?;
+        let mut certs = X509::stack_from_pem(&pem)
+            .context(InvalidPemSnafu { path: cert_path })?
+            .into_iter();
+        let leaf = certs.next().ok_or_else(|| TlsError::EmptyCert {
+            path: cert_path.to_string(),
+        })?;
+        let pem = std::fs::read(key_path).context(ReadFileSnafu { path: key_path })?;
+        let key = PKey::private_key_from_pem(&pem).context(InvalidPemSnafu { path: key_path })?;
+        let matched = leaf.public_key().is_ok_and(|public| public.public_eq(&key));
+        if !matched {
+            return Err(TlsError::KeyMismatch {
+                path: cert_path.to_string(),
+            });
+        }
+        Ok(Self {
+            leaf,
+            chain: certs.collect(),
+            key,
+        })
+    }
+
+    fn apply(&self, ssl: &mut SslRef) -> Result<(), openssl::error::ErrorStack> {
+        ext::ssl_use_certificate(ssl, &self.leaf)?;
+        ext::ssl_use_private_key(ssl, &self.key)?;
+        for cert in &self.chain {
+            ext::ssl_add_chain_cert(ssl, cert)?;
+        }
+        Ok(())
+    }
+}
+
+/// Certificates of a listener indexed by server name
+pub struct CertStore {
+    default: Arc<CertKey>,
+    exact: HashMap<String, Arc<CertKey>>,
+    /// Wildcard certificates indexed by the parent domain, e.g. `example.com` for `*.example.com`
+    wildcard: HashMap<String, Arc<CertKey>>,
+}
+
+impl CertStore {
+    pub fn load(cfg: &SslConfig) -> TlsResult<Self> {
+        let mut store = Self {
+            default: Arc::new(CertKey::load(&cfg.cert_path, &cfg.key_path)?),
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        };
+        for cert in &cfg.certificates {
+            let cert_key = Arc::new(CertKey::load(&cert.cert_path, &cert.key_path)?);
+            for name in &cert.server_names {
+                let name = name.to_lowercase();
+                match name.strip_prefix("*.") {
+                    Some(parent) => store.wildcard.insert(parent.to_string(), cert_key.clone()),
+                    None => store.exact.insert(name, cert_key.clone()),
+                };
+            }
+        }
+        Ok(store)
+    }
+
+    /// Selects the certificate of the server name, exact names take precedence over wildcards
+    fn select(&self, server_name: Option<&str>) -> Arc<CertKey> {
+        let Some(name) = server_name.map(|name| name.to_lowercase()) else {
+            return self.default.clone();
+        };
+        self.exact
+            .get(&name)
+            .or_else(|| {
+                name.split_once('.')
+                    .and_then(|(_, parent)| self.wildcard.get(parent))
+            })
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+/// Provides the certificate matching the SNI during the handshake
+pub struct CertResolver {
+    store: Arc<ArcSwap<CertStore>>,
+}
+
+#[async_trait]
+impl TlsAccept for CertResolver {
+    async fn certificate_callback(&self, ssl: &mut SslRef) -> () {
+        let cert = self
+            .store
+            .load()
+            .select(ssl.servername(NameType::HOST_NAME));
+        if let Err(e) = cert.apply(ssl) {
+            error!("failed to use certificate, error: {}", e);
+        }
+    }
+}
+
+/// Reloads the certificates of a listener when their files change or on SIGHUP
+pub struct CertWatcher {
+    listener: String,
+    cfg: SslConfig,
+    store: Arc<ArcSwap<CertStore>>,
+}
+
+impl CertWatcher {
+    /// Modification times of the certificate and key files of the listener
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        std::iter::once((&self.cfg.cert_path, &self.cfg.key_path))
+            .chain(
+                self.cfg
+                    .certificates
+                    .iter()
+                    .map(|cert| (&cert.cert_path, &cert.key_path)),
+            )
+            .flat_map(|(cert, key)| [cert, key])
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Swaps in the certificates once all of them are loaded and validated, the previous ones
+    /// stay in use on failure
+    fn reload(&self) {
+        match CertStore::load(&self.cfg) {
+            Ok(store) => {
+                self.store.store(Arc::new(store));
+                info!("reloaded certificates of listener {}", self.listener);
+            }
+            Err(e) => error!(
+                "failed to reload certificates of listener {}, keeping the previous ones, error: {}",
+                self.listener, e
+            ),
+        }
+    }
+
+    async fn watch(self: Arc<Self>, mut shutdown: ShutdownWatch) {
+        let mut hangup = signal(SignalKind::hangup())
+            .inspect_err(|e| error!("failed to listen for SIGHUP, error: {}", e))
+            .ok();
+        let mut interval = tokio::time::interval(self.cfg.reload_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut modified = self.modified();
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {
+                    let current = self.modified();
+                    if current != modified {
+                        modified = current;
+                        self.reload();
+                    }
+                }
+                Some(_) = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    modified = self.modified();
+                    self.reload();
+                }
+            }
+        }
+    }
+}
+
+/// Background service watching the certificates of all TLS listeners
+#[derive(Default)]
+pub struct CertReloader {
+    watchers: Vec<Arc<CertWatcher>>,
+}
+
+impl CertReloader {
+    pub fn add(&mut self, watcher: CertWatcher) {
+        self.watchers.push(Arc::new(watcher));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CertReloader {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        let tasks: Vec<_> = self
+            .watchers
+            .iter()
+            .map(|watcher| tokio::spawn(watcher.clone().watch(shutdown.clone())))
+            .collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls1 => SslVersion::TLS1,
+        TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+        TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+        TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+    }
+}
+
+/// Builds the TLS settings of a listener along with the watcher reloading its certificates
+///
+/// Certificates are selected by SNI at handshake time, client certificates are verified if
+/// `client_ca` is set.
+pub fn build_tls_settings(listener: &str, cfg: SslConfig) -> TlsResult<(TlsSettings, CertWatcher)> {
+    let store = Arc::new(ArcSwap::from_pointee(CertStore::load(&cfg)?));
+    let resolver = CertResolver {
+        store: store.clone(),
+    };
+    let mut settings = TlsSettings::with_callbacks(Box::new(resolver)).context(PingoraSnafu)?;
+    if let Some(version) = cfg.min_version {
+        settings
+            .set_min_proto_version(Some(ssl_version(version)))
+            .context(SettingSnafu {
+                setting: "min_version",
+            })?;
+    }
+    if let Some(version) = cfg.max_version {
+        settings
+            .set_max_proto_version(Some(ssl_version(version)))
+            .context(SettingSnafu {
+                setting: "max_version",
+            })?;
+    }
+    if let Some(ciphers) = &cfg.ciphers {
+        settings
+            .set_cipher_list(ciphers)
+            .context(SettingSnafu { setting: "ciphers" })?;
+    }
+    if let Some(ciphersuites) = &cfg.ciphersuites {
+        settings
+            .set_ciphersuites(ciphersuites)
+            .context(SettingSnafu {
+                setting: "ciphersuites",
+            })?;
+    }
+    if let Some(ca) = &cfg.client_ca {
+        settings.set_ca_file(ca).context(SettingSnafu {
+            setting: "client_ca",
+        })?;
+        settings.set_client_ca_list(X509Name::load_client_ca_file(ca).context(SettingSnafu {
+            setting: "client_ca",
+        })?);
+        let mode = match cfg.client_verify {
+            ClientVerify::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            ClientVerify::Optional => SslVerifyMode::PEER,
+        };
+        settings.set_verify(mode);
+    }
+    let watcher = CertWatcher {
+        listener: listener.to_string(),
+        cfg,
+        store,
+    };
+    Ok((settings, watcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        x509::X509NameBuilder,
+    };
+
+    use super::*;
+
+    fn cert_key(cn: &str) -> Arc<CertKey> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut leaf = X509::builder().unwrap();
+        leaf.set_subject_name(&name).unwrap();
+        leaf.set_issuer_name(&name).unwrap();
+        leaf.set_pubkey(&key).unwrap();
+        leaf.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        leaf.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        leaf.sign(&key, MessageDigest::sha256()).unwrap();
+        Arc::new(CertKey {
+            leaf: leaf.build(),
+            chain: vec![],
+            key,
+        })
+    }
+
+    fn cn(cert: &CertKey) -> String {
+        let entry = cert.leaf.subject_name().entries().next().unwrap();
+        entry.data().as_utf8().unwrap().to_string()
+    }
+
+    #[test]
+    fn select_prefers_exact_names_over_wildcards() {
+        let store = CertStore {
+            default: cert_key("default"),
+            exact: HashMap::from([("api.example.com".to_string(), cert_key("exact"))]),
+            wildcard: HashMap::from([("example.com".to_string(), cert_key("wildcard"))]),
+        };
+        let select = |name| cn(&store.select(name));
+        assert_eq!(select(Some("api.example.com")), "exact");
+        assert_eq!(select(Some("API.Example.com")), "exact");
+        assert_eq!(select(Some("www.example.com")), "wildcard");
+        // wildcards only cover a single label
+        assert_eq!(select(Some("a.b.example.com")), "default");
+        assert_eq!(select(Some("example.com")), "default");
+        assert_eq!(select(Some("other.org")), "default");
+        assert_eq!(select(None), "default");
+    }
+}
@@ -0,0 +1,12 @@
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum TemplateError {
+    #[snafu(display("Unclosed variable in template: {}", template))]
+    Unclosed { template: String },
+    #[snafu(display("Unknown variable in template: {}", var))]
+    UnknownVariable { var: String },
+    #[snafu(display("Invalid header name in template: {}", name))]
+    InvalidHeader { name: String },
+}
@@ -1,13 +1,68 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap, net::IpAddr};
 
 use bytes::Bytes;
-use http::{header, Response, StatusCode};
+use http::{header, uri::PathAndQuery, Response, StatusCode, Uri};
 use pingora::{http::ResponseHeader, prelude::*};
 
+use crate::core::plugin::ClientCert;
+
+pub mod errors;
+pub mod template;
+
+/// Returns the ip address of the downstream peer, if it is an inet socket
+///
+/// IPv4 peers of dual-stack listeners are returned as IPv4 rather than IPv4-mapped IPv6.
+pub fn client_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip().to_canonical())
+}
+
+/// Returns the certificate the client presented in the TLS handshake
+///
+/// Only HTTP/1 connections expose the TLS session, listeners don't negotiate HTTP/2.
+pub fn client_cert(session: &Session) -> Option<ClientCert> {
+    let cert = session.stream()?.get_ssl()?.peer_certificate()?;
+    Some(ClientCert::new(&cert))
+}
+
+/// Returns the percent-decoded value of the first query parameter named `name`
+pub fn query_param<'a>(req: &'a RequestHeader, name: &str) -> Option<Cow<'a, str>> {
+    form_urlencoded::parse(req.uri.query()?.as_bytes())
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v)
+}
+
+/// Removes all query parameters named `name` from the request uri
+pub fn remove_query_param(req: &mut RequestHeader, name: &str) {
+    let Some(query) = req.uri.query() else {
+        return;
+    };
+    let query = query
+        .split('&')
+        .filter(|pair| pair.split_once('=').map_or(*pair, |(k, _)| k) != name)
+        .collect::<Vec<_>>()
+        .join("&");
+    let path_and_query = if query.is_empty() {
+        req.uri.path().to_string()
+    } else {
+        format!("{}?{}", req.uri.path(), query)
+    };
+    let mut parts = req.uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse::<PathAndQuery>().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        req.set_uri(uri);
+    }
+}
+
+/// Sends a response generated by the gateway
+///
+/// Fails instead of sending anything if a header name or value is invalid.
 pub async fn send_response(
     session: &mut Session,
     status: StatusCode,
-    content_type: Option<&'static str>,
+    content_type: Option<&str>,
     body: Option<Bytes>,
     headers: Option<HashMap<String, String>>,
 ) -> Result<()> {
@@ -27,7 +82,9 @@ pub async fn send_response(
     }
 
     if let Some(body) = body {
-        let resp = bd.body(body).unwrap();
+        let resp = bd
+            .body(body)
+            .or_err(InternalError, "invalid response header")?;
         let (parts, body) = resp.into_parts();
         let resp_header: ResponseHeader = parts.into();
         session
@@ -35,7 +92,9 @@ pub async fn send_response(
             .await?;
         session.write_response_body(Some(body), true).await
     } else {
-        let resp = bd.body(()).unwrap();
+        let resp = bd
+            .body(())
+            .or_err(InternalError, "invalid response header")?;
         let (parts, _) = resp.into_parts();
         let resp_header: ResponseHeader = parts.into();
         session
@@ -44,3 +103,15 @@ pub async fn send_response(
         session.write_response_body(None, true).await
     }
 }
+
+/// Creates a session which already read the header of the raw HTTP/1 request `req`
+#[cfg(test)]
+pub(crate) async fn test_session(req: &str) -> Session {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut client, server) = tokio::io::duplex(4096);
+    client.write_all(req.as_bytes()).await.unwrap();
+    let mut session = Session::new_h1(Box::new(server));
+    session.read_request().await.unwrap();
+    session
+}
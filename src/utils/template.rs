@@ -0,0 +1,245 @@
+use std::{borrow::Cow, str::FromStr};
+
+use http::{header, HeaderName};
+use pingora::prelude::*;
+
+use crate::{
+    core::plugin::PluginCtx,
+    utils::{client_ip, errors::*},
+};
+
+/// A string with `${variable}` placeholders that are filled from the current request
+///
+/// Supported variables:
+/// * `method`, `uri`, `path`, `query`, `host`
+/// * `client_ip` - ip address of the client, see [`PluginCtx::client_ip`]
+/// * `identity` - name of the authenticated identity, see [`PluginCtx::identity`]
+/// * `route` - name of the matched route
+/// * `request_id` - id of the request, see [`PluginCtx::request_id`], falls back to the
+///   `X-Request-Id` header
+/// * `header.<name>` - value of the request header `<name>`
+/// * `param.<index|name>` - parameter captured by the route matcher
+///
+/// Variables that can't be resolved for a request are rendered as an empty string, and `$${`
+/// is rendered as a literal `${`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Var(Variable),
+}
+
+#[derive(Debug, Clone)]
+enum Variable {
+    Method,
+    Uri,
+    Path,
+    Query,
+    Host,
+    ClientIp,
+    Identity,
+    Route,
+    RequestId,
+    Header(HeaderName),
+    ParamIndex(usize),
+    ParamName(String),
+}
+
+impl Template {
+    /// Parses a template, failing on unclosed placeholders and unknown variables
+    pub fn parse(tpl: &str) -> Result<Self, TemplateError> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = tpl;
+        while let Some(start) = rest.find("${") {
+            // `$${` escapes the placeholder
+            if rest[..start].ends_with('$') {
+                literal.push_str(&rest[..start - 1]);
+                literal.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            literal.push_str(&rest[..start]);
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed {
+                template: tpl.to_string(),
+            })?;
+            segments.push(Segment::Var(rest[start + 2..start + end].trim().parse()?));
+            rest = &rest[start + end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    /// Returns the rendered string if the template doesn't contain any variable
+    pub fn as_static(&self) -> Option<String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Var(_) => return None,
+            }
+        }
+        Some(out)
+    }
+
+    /// Renders the template against the current request
+    pub fn render(&self, session: &Session, ctx: &PluginCtx) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Var(var) => out.push_str(&var.resolve(session, ctx)),
+            }
+        }
+        out
+    }
+}
+
+impl FromStr for Variable {
+    type Err = TemplateError;
+
+    fn from_str(var: &str) -> Result<Self, Self::Err> {
+        let v = match var {
+            "method" => Variable::Method,
+            "uri" => Variable::Uri,
+            "path" => Variable::Path,
+            "query" => Variable::Query,
+            "host" => Variable::Host,
+            "client_ip" => Variable::ClientIp,
+            "identity" => Variable::Identity,
+            "route" => Variable::Route,
+            "request_id" => Variable::RequestId,
+            _ => {
+                if let Some(name) = var.strip_prefix("header.") {
+                    Variable::Header(HeaderName::from_str(name).map_err(|_| {
+                        TemplateError::InvalidHeader {
+                            name: name.to_string(),
+                        }
+                    })?)
+                } else if let Some(param) = var.strip_prefix("param.") {
+                    match param.parse() {
+                        Ok(idx) => Variable::ParamIndex(idx),
+                        Err(_) => Variable::ParamName(param.to_string()),
+                    }
+                } else {
+                    return Err(TemplateError::UnknownVariable {
+                        var: var.to_string(),
+                    });
+                }
+            }
+        };
+        Ok(v)
+    }
+}
+
+impl Variable {
+    fn resolve<'a>(&self, session: &'a Session, ctx: &'a PluginCtx) -> Cow<'a, str> {
+        let req = session.req_header();
+        match self {
+            Variable::Method => Cow::Borrowed(req.method.as_str()),
+            Variable::Uri => Cow::Owned(req.uri.to_string()),
+            Variable::Path => Cow::Borrowed(req.uri.path()),
+            Variable::Query => Cow::Borrowed(req.uri.query().unwrap_or_default()),
+            Variable::Host => Cow::Borrowed(
+                req.uri
+                    .host()
+                    .or_else(|| req.headers.get(header::HOST).and_then(|v| v.to_str().ok()))
+                    .unwrap_or_default(),
+            ),
+            Variable::ClientIp => {
+                let ip = ctx.client_ip.or_else(|| client_ip(session));
+                Cow::Owned(ip.map_or(String::new(), |ip| ip.to_string()))
+            }
+            Variable::Identity => Cow::Borrowed(ctx.identity.as_deref().unwrap_or_default()),
+            Variable::Route => Cow::Borrowed(ctx.route.as_deref().unwrap_or_default()),
+            Variable::RequestId => Cow::Borrowed(
+                ctx.request_id
+                    .as_deref()
+                    .or_else(|| {
+                        req.headers
+                            .get("x-request-id")
+                            .and_then(|v| v.to_str().ok())
+                    })
+                    .unwrap_or_default(),
+            ),
+            Variable::Header(name) => Cow::Borrowed(
+                req.headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default(),
+            ),
+            Variable::ParamIndex(idx) => Cow::Borrowed(
+                ctx.route_params
+                    .as_ref()
+                    .and_then(|p| p.get(*idx))
+                    .unwrap_or_default(),
+            ),
+            Variable::ParamName(name) => Cow::Borrowed(
+                ctx.route_params
+                    .as_ref()
+                    .and_then(|p| p.get_by_name(name))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_session;
+
+    #[test]
+    fn escaped_placeholders_are_literal() {
+        let tpl = Template::parse("a $${route} b $$ c").unwrap();
+        assert_eq!(tpl.as_static().as_deref(), Some("a ${route} b $$ c"));
+        let tpl = Template::parse("$${x}${route}").unwrap();
+        assert!(tpl.as_static().is_none());
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!(matches!(
+            Template::parse("a ${route"),
+            Err(TemplateError::Unclosed { .. })
+        ));
+        assert!(matches!(
+            Template::parse("${nope}"),
+            Err(TemplateError::UnknownVariable { .. })
+        ));
+        assert!(matches!(
+            Template::parse("${header.a b}"),
+            Err(TemplateError::InvalidHeader { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn variables_are_resolved_from_the_request() {
+        let session = test_session(
+            "GET /users/42?page=2 HTTP/1.1\r\nHost: example.com\r\nX-Tenant: acme\r\n\r\n",
+        )
+        .await;
+        let mut ctx = PluginCtx::default();
+        ctx.route = Some("users".to_string());
+        ctx.identity = Some("alice".to_string());
+        let tpl = Template::parse(
+            "${method} ${path}?${query} ${host} ${ header.x-tenant } ${identity}@${route} \
+             [${request_id}] [${param.0}] $${host}",
+        )
+        .unwrap();
+        assert_eq!(
+            tpl.render(&session, &ctx),
+            "GET /users/42?page=2 example.com acme alice@users [] [] ${host}"
+        );
+    }
+}